@@ -135,6 +135,7 @@ pub const LIBSSH2_KNOWNHOST_CHECK_FAILURE: c_int = 3;
 pub const LIBSSH2_KNOWNHOST_TYPE_PLAIN: c_int = 1;
 pub const LIBSSH2_KNOWNHOST_TYPE_SHA1: c_int = 2;
 pub const LIBSSH2_KNOWNHOST_TYPE_CUSTOM: c_int = 3;
+pub const LIBSSH2_KNOWNHOST_TYPE_MASK: c_int = 0xffff;
 pub const LIBSSH2_KNOWNHOST_KEYENC_RAW: c_int = 1 << 16;
 pub const LIBSSH2_KNOWNHOST_KEYENC_BASE64: c_int = 2 << 16;
 pub const LIBSSH2_KNOWNHOST_KEY_RSA1: c_int = 1 << 18;
@@ -145,6 +146,7 @@ pub const LIBSSH2_KNOWNHOST_KEY_ECDSA_384: c_int = 5 << 18;
 pub const LIBSSH2_KNOWNHOST_KEY_ECDSA_521: c_int = 6 << 18;
 pub const LIBSSH2_KNOWNHOST_KEY_ED25519: c_int = 7 << 18;
 pub const LIBSSH2_KNOWNHOST_KEY_UNKNOWN: c_int = 15 << 18;
+pub const LIBSSH2_KNOWNHOST_KEY_MASK: c_int = 15 << 18;
 
 pub const LIBSSH2_FXF_READ: c_ulong = 0x00000001;
 pub const LIBSSH2_FXF_WRITE: c_ulong = 0x00000002;
@@ -188,6 +190,16 @@ pub const LIBSSH2_CHANNEL_EXTENDED_DATA_MERGE: c_int = 2;
 pub const LIBSSH2_SESSION_BLOCK_INBOUND: c_int = 1;
 pub const LIBSSH2_SESSION_BLOCK_OUTBOUND: c_int = 2;
 
+pub const LIBSSH2_TRACE_TRANS: c_int = 1 << 1;
+pub const LIBSSH2_TRACE_KEX: c_int = 1 << 2;
+pub const LIBSSH2_TRACE_AUTH: c_int = 1 << 3;
+pub const LIBSSH2_TRACE_CONN: c_int = 1 << 4;
+pub const LIBSSH2_TRACE_SCP: c_int = 1 << 5;
+pub const LIBSSH2_TRACE_SFTP: c_int = 1 << 6;
+pub const LIBSSH2_TRACE_ERROR: c_int = 1 << 7;
+pub const LIBSSH2_TRACE_PUBLICKEY: c_int = 1 << 8;
+pub const LIBSSH2_TRACE_SOCKET: c_int = 1 << 9;
+
 pub enum LIBSSH2_SESSION {}
 pub enum LIBSSH2_AGENT {}
 pub enum LIBSSH2_CHANNEL {}
@@ -273,6 +285,13 @@ pub type LIBSSH2_PASSWD_CHANGEREQ_FUNC = extern "C" fn(
     abstrakt: *mut *mut c_void,
 );
 
+pub type libssh2_trace_handler_func = extern "C" fn(
+    sess: *mut LIBSSH2_SESSION,
+    context: *mut c_void,
+    data: *const c_char,
+    length: size_t,
+);
+
 pub type LIBSSH2_USERAUTH_KBDINT_RESPONSE_FUNC = extern "C" fn(
     username: *const c_char,
     username_len: c_int,
@@ -366,6 +385,12 @@ extern "C" {
     pub fn libssh2_keepalive_send(sess: *mut LIBSSH2_SESSION, seconds_to_next: *mut c_int)
         -> c_int;
     pub fn libssh2_session_block_directions(sess: *mut LIBSSH2_SESSION) -> c_int;
+    pub fn libssh2_trace(sess: *mut LIBSSH2_SESSION, bitmask: c_int) -> c_int;
+    pub fn libssh2_trace_sethandler(
+        sess: *mut LIBSSH2_SESSION,
+        context: *mut c_void,
+        callback: Option<libssh2_trace_handler_func>,
+    ) -> c_int;
 
     // agent
     pub fn libssh2_agent_init(sess: *mut LIBSSH2_SESSION) -> *mut LIBSSH2_AGENT;
@@ -661,6 +686,12 @@ extern "C" {
         handle: *mut LIBSSH2_SFTP_HANDLE,
         attrs: *mut LIBSSH2_SFTP_STATVFS,
     ) -> c_int;
+    pub fn libssh2_sftp_statvfs(
+        sftp: *mut LIBSSH2_SFTP,
+        path: *const c_char,
+        path_len: size_t,
+        attrs: *mut LIBSSH2_SFTP_STATVFS,
+    ) -> c_int;
     pub fn libssh2_sftp_stat_ex(
         sftp: *mut LIBSSH2_SFTP,
         path: *const c_char,
@@ -0,0 +1,230 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use sftp::{File, FileStat, Sftp};
+use util;
+
+const BLOCK: usize = 512;
+
+/// A tar stream of a remote directory tree.
+///
+/// This is created by `Sftp::archive_reader`. The tree is walked depth
+/// first and lazily as the stream is read: directories are listed and files
+/// opened only once the data before them has been consumed. The output is a ustar archive,
+/// using the GNU extensions for names and link targets longer than 100
+/// bytes and for files of 8GiB or more.
+///
+/// Entries are named relative to the root of the walk. Regular files,
+/// directories and symlinks are archived; other kinds of files are skipped.
+pub struct ArchiveReader {
+    sftp: Sftp,
+    root: PathBuf,
+    // Entries listed but not archived yet, next one first
+    entries: VecDeque<(PathBuf, FileStat)>,
+    // Headers and padding waiting to be handed out
+    pending: Vec<u8>,
+    pending_pos: usize,
+    // The file whose contents are being streamed, its size and how much of
+    // it is left
+    file: Option<(File, u64, u64)>,
+    started: bool,
+    finished: bool,
+}
+
+impl ArchiveReader {
+    pub(crate) fn new(sftp: Sftp, root: &Path) -> ArchiveReader {
+        ArchiveReader {
+            sftp,
+            root: root.to_path_buf(),
+            entries: VecDeque::new(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            file: None,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Queues up the headers of the next entry of the walk, or the end of
+    /// the archive. Returns false once everything has been queued.
+    fn next_entry(&mut self) -> io::Result<bool> {
+        self.pending.clear();
+        self.pending_pos = 0;
+        loop {
+            let (name, stat) = match self.entries.pop_front() {
+                Some(entry) => entry,
+                None if !self.started => {
+                    self.started = true;
+                    self.list(Path::new(""))?;
+                    continue;
+                }
+                None if self.finished => return Ok(false),
+                None => {
+                    // Two zero blocks mark the end of the archive
+                    self.pending.resize(2 * BLOCK, 0);
+                    self.finished = true;
+                    return Ok(true);
+                }
+            };
+
+            let file_type = stat.file_type();
+            if file_type.is_dir() {
+                let mut path = util::path2bytes(&name)?.into_owned();
+                path.push(b'/');
+                self.push_header(&path, b"", b'5', &stat, 0);
+                self.list(&name)?;
+            } else if file_type.is_file() {
+                let path = util::path2bytes(&name)?.into_owned();
+                let size = stat.size.unwrap_or(0);
+                self.push_header(&path, b"", b'0', &stat, size);
+                if size > 0 {
+                    let file = self.sftp.open(&self.root.join(&name))?;
+                    self.file = Some((file, size, size));
+                }
+            } else if file_type.is_symlink() {
+                let path = util::path2bytes(&name)?.into_owned();
+                let target = self.sftp.readlink(&self.root.join(&name))?;
+                let target = util::path2bytes(&target)?.into_owned();
+                self.push_header(&path, &target, b'2', &stat, 0);
+            } else {
+                continue;
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Puts the contents of the directory `dir` first in line, so that the
+    /// tree is archived depth first.
+    fn list(&mut self, dir: &Path) -> io::Result<()> {
+        let mut entries = self.sftp.readdir(&self.root.join(dir))?;
+        entries.sort_by(|a, b| b.0.cmp(&a.0));
+        for (path, stat) in entries {
+            let name = dir.join(path.file_name().expect("filtered by readdir"));
+            self.entries.push_front((name, stat));
+        }
+        Ok(())
+    }
+
+    fn push_header(&mut self, name: &[u8], link: &[u8], kind: u8, stat: &FileStat, size: u64) {
+        if name.len() > 100 {
+            self.push_long_name(b'L', name, stat);
+        }
+        if link.len() > 100 {
+            self.push_long_name(b'K', link, stat);
+        }
+        let mut header = [0; BLOCK];
+        copy_truncated(&mut header[0..100], name);
+        write_number(
+            &mut header[100..108],
+            u64::from(stat.perm.unwrap_or(0) & 0o7777),
+        );
+        write_number(&mut header[108..116], u64::from(stat.uid.unwrap_or(0)));
+        write_number(&mut header[116..124], u64::from(stat.gid.unwrap_or(0)));
+        write_number(&mut header[124..136], size);
+        write_number(&mut header[136..148], stat.mtime.unwrap_or(0));
+        header[156] = kind;
+        copy_truncated(&mut header[157..257], link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        write_checksum(&mut header);
+        self.pending.extend_from_slice(&header);
+    }
+
+    /// Queues a GNU extension entry holding a name too long for the header
+    /// of the entry that follows it.
+    fn push_long_name(&mut self, kind: u8, name: &[u8], stat: &FileStat) {
+        let len = name.len() as u64 + 1;
+        self.push_header(b"././@LongLink", b"", kind, stat, len);
+        self.pending.extend_from_slice(name);
+        self.pending.push(0);
+        pad(&mut self.pending, len);
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pending_pos < self.pending.len() {
+                let n = cmp::min(buf.len(), self.pending.len() - self.pending_pos);
+                buf[..n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+                self.pending_pos += n;
+                return Ok(n);
+            }
+
+            let done = match self.file {
+                Some((ref mut file, _, ref mut remaining)) => {
+                    let max = cmp::min(buf.len() as u64, *remaining) as usize;
+                    let n = file.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "remote file shrank while being archived",
+                        ));
+                    }
+                    *remaining -= n as u64;
+                    if *remaining > 0 {
+                        return Ok(n);
+                    }
+                    Some(n)
+                }
+                None => None,
+            };
+            if let Some(n) = done {
+                // The contents are complete: pad them to a whole block
+                let (_, size, _) = self.file.take().expect("matched above");
+                self.pending.clear();
+                self.pending_pos = 0;
+                pad(&mut self.pending, size);
+                return Ok(n);
+            }
+
+            if !self.next_entry()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+fn copy_truncated(field: &mut [u8], value: &[u8]) {
+    let n = cmp::min(field.len(), value.len());
+    field[..n].copy_from_slice(&value[..n]);
+}
+
+/// Writes `value` as a NUL-terminated octal number, or in the GNU base-256
+/// encoding if it does not fit.
+fn write_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if digits * 3 >= 64 || value < 1 << (digits * 3) {
+        let octal = format!("{:0width$o}", value, width = digits);
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        for (i, byte) in field.iter_mut().rev().enumerate() {
+            *byte = if i < 8 { (value >> (i * 8)) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
+
+fn write_checksum(header: &mut [u8; BLOCK]) {
+    for byte in &mut header[148..156] {
+        *byte = b' ';
+    }
+    let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    let octal = format!("{:06o}\0 ", sum);
+    header[148..156].copy_from_slice(octal.as_bytes());
+}
+
+fn pad(buf: &mut Vec<u8>, len: u64) {
+    let rem = (len % BLOCK as u64) as usize;
+    if rem != 0 {
+        let new_len = buf.len() + BLOCK - rem;
+        buf.resize(new_len, 0);
+    }
+}
@@ -0,0 +1,158 @@
+use parking_lot::Mutex;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use {raw, util, File, SessionInner};
+
+/// An SFTP file usable with tokio, through its `AsyncRead` and `AsyncWrite`
+/// implementations.
+///
+/// This is only available with the `tokio` feature, on unix.
+///
+/// The session is put in non-blocking mode, and its socket is registered
+/// with the tokio reactor; the direction libssh2 is blocked on, as told by
+/// `Session::block_directions`, decides whether a task waits for the socket
+/// to be readable or writable. For this to work:
+///
+/// * the socket given to `Session::set_tcp_stream` must be a plain socket,
+///   which is the case of a `std::net::TcpStream`;
+/// * the `AsyncSftpFile` must be created from within a tokio runtime, with
+///   its IO driver enabled;
+/// * the session must not be switched back to blocking mode while it is in
+///   use, including by other objects created from it.
+///
+/// Other objects of the session can be used from other tasks at the same
+/// time, as long as they handle `LIBSSH2_ERROR_EAGAIN` errors. Calls are
+/// still serialized by the lock on the session.
+pub struct AsyncSftpFile {
+    file: File,
+    sess: Arc<Mutex<SessionInner>>,
+    fd: AsyncFd<DupFd>,
+}
+
+/// A duplicate of the session socket, so that several files of one session
+/// can each register it with the reactor.
+struct DupFd(RawFd);
+
+impl AsyncSftpFile {
+    /// Wraps `file`, putting its session in non-blocking mode.
+    ///
+    /// Fails if the file is closed, if the session has no socket or if it
+    /// can't be registered with the reactor of the current runtime.
+    pub fn new(file: File) -> io::Result<AsyncSftpFile> {
+        let sess = file
+            .session()
+            .ok_or_else(|| util::io_other("file is closed"))?;
+        let fd = {
+            let inner = sess.lock();
+            let fd = inner
+                .raw_fd()
+                .ok_or_else(|| util::io_other("session has no socket to wait on"))?;
+            inner.set_blocking(false);
+            fd
+        };
+        let dup = unsafe { libc::dup(fd) };
+        if dup < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = AsyncFd::new(DupFd(dup))?;
+        Ok(AsyncSftpFile { file, sess, fd })
+    }
+
+    /// Returns the wrapped file, for the operations which have no
+    /// asynchronous version. Those then fail with `LIBSSH2_ERROR_EAGAIN`
+    /// errors rather than blocking.
+    pub fn get_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Returns the wrapped file. The session is left in non-blocking mode.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Runs `op` until it doesn't report that it would block, waiting for
+    /// the socket to be ready in between.
+    fn poll_io<T, F>(&mut self, cx: &mut Context, mut op: F) -> Poll<io::Result<T>>
+    where
+        F: FnMut(&mut File) -> io::Result<T>,
+    {
+        loop {
+            match op(&mut self.file) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                res => return Poll::Ready(res),
+            }
+            let directions = unsafe { raw::libssh2_session_block_directions(self.sess.lock().raw) };
+            let mut guard = if directions == raw::LIBSSH2_SESSION_BLOCK_OUTBOUND {
+                match self.fd.poll_write_ready(cx) {
+                    Poll::Ready(guard) => guard?,
+                    Poll::Pending => return Poll::Pending,
+                }
+            } else {
+                match self.fd.poll_read_ready(cx) {
+                    Poll::Ready(guard) => guard?,
+                    Poll::Pending => return Poll::Pending,
+                }
+            };
+            match op(&mut self.file) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => guard.clear_ready(),
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+impl AsyncRead for AsyncSftpFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let dst = buf.initialize_unfilled();
+        match self.get_mut().poll_io(cx, |file| file.read(dst)) {
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for AsyncSftpFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(cx, |file| file.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        // Writes are sent to the server as they are made
+        Poll::Ready(Ok(()))
+    }
+
+    /// Closes the remote handle.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_io(cx, |file| file.close().map_err(io::Error::from))
+    }
+}
+
+impl AsRawFd for DupFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for DupFd {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = libc::close(self.0);
+        }
+    }
+}
@@ -1,10 +1,14 @@
-use libc::{c_char, c_int, c_uchar, c_uint, c_ulong, c_void, size_t};
+use libc::{self, c_char, c_int, c_uchar, c_uint, c_ulong, c_void, size_t};
 use parking_lot::{Mutex, MutexGuard};
 use std::cmp;
 use std::io;
 use std::io::prelude::*;
+use std::mem;
+use std::ptr;
 use std::slice;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use {raw, Error, ExtendedData, PtyModes, SessionInner};
 
@@ -96,6 +100,17 @@ pub struct ExitSignal {
     pub lang_tag: Option<String>,
 }
 
+/// Output of a command run with `Channel::exec_capture`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Everything the command wrote to its standard output.
+    pub stdout: Vec<u8>,
+    /// Everything the command wrote to its standard error.
+    pub stderr: Vec<u8>,
+    /// The exit status of the command.
+    pub exit_status: i32,
+}
+
 /// Description of the read window as returned by `Channel::read_window`
 #[derive(Copy, Clone)]
 pub struct ReadWindow {
@@ -298,6 +313,25 @@ impl Channel {
         }
     }
 
+    /// Discard the data received on a stream of this channel that has not
+    /// been read yet, returning how many bytes were dropped.
+    ///
+    /// This is what libssh2 calls flushing a channel, and what `flush` on a
+    /// `Stream` does: it only concerns incoming data. Outgoing data never
+    /// needs flushing, as a successful `write` has already handed the data
+    /// over to the transport; a prompt written to the channel is on its way
+    /// once `write` returns.
+    ///
+    /// `stream_id` is either a stream number, such as 0 for stdout or
+    /// `EXTENDED_DATA_STDERR`, or one of `FLUSH_EXTENDED_DATA` and
+    /// `FLUSH_ALL`.
+    pub fn flush_stream(&mut self, stream_id: i32) -> Result<u64, Error> {
+        let locked = self.lock();
+        let rc = unsafe { raw::libssh2_channel_flush_ex(locked.raw, stream_id as c_int) };
+        locked.sess.rc(rc)?;
+        Ok(rc as u64)
+    }
+
     /// Change how extended data (such as stderr) is handled
     pub fn handle_extended_data(&mut self, mode: ExtendedData) -> Result<(), Error> {
         let locked = self.lock();
@@ -387,6 +421,132 @@ impl Channel {
         }
     }
 
+    /// Wait until data can be read from the channel, on any of its streams,
+    /// or until the remote end has sent EOF, returning whether it happened
+    /// before `timeout` expired.
+    ///
+    /// The packets which already arrived are processed in non-blocking mode,
+    /// then the socket is waited on in the directions given by
+    /// `Session::block_directions`, without keeping the session locked.
+    /// Another thread may therefore read the data first. With no timeout,
+    /// this waits for as long as needed.
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> Result<bool, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let directions = self.pump()?;
+            if self.is_readable() {
+                return Ok(true);
+            }
+            match remaining_until(deadline) {
+                Some(remaining) => self.wait_socket(directions, remaining),
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /// Processes the packets which already arrived on the session, without
+    /// blocking, and returns the directions it would block in.
+    pub(crate) fn pump(&self) -> Result<c_int, Error> {
+        let locked = self.lock();
+        let was_blocking = locked.sess.is_blocking();
+        locked.sess.set_blocking(false);
+        // Reading nothing still processes the pending packets
+        let rc = unsafe { raw::libssh2_channel_read_ex(locked.raw, 0, ptr::null_mut(), 0) };
+        let directions = unsafe { raw::libssh2_session_block_directions(locked.sess.raw) };
+        locked.sess.set_blocking(was_blocking);
+        if rc as c_int != raw::LIBSSH2_ERROR_EAGAIN {
+            locked.sess.rc(rc as c_int)?;
+        }
+        Ok(directions)
+    }
+
+    /// Whether data was received on any stream, or EOF, as of the last
+    /// packets processed.
+    pub(crate) fn is_readable(&self) -> bool {
+        let locked = self.lock();
+        let mut avail = 0;
+        unsafe {
+            let _ = raw::libssh2_channel_window_read_ex(locked.raw, &mut avail, ptr::null_mut());
+            avail > 0 || raw::libssh2_channel_eof(locked.raw) != 0
+        }
+    }
+
+    /// Whether this channel was opened on the session `sess`.
+    pub(crate) fn belongs_to(&self, sess: &Arc<Mutex<SessionInner>>) -> bool {
+        Arc::ptr_eq(&self.channel_inner.sess, sess)
+    }
+
+    /// Reads from the main stream without blocking, whatever the mode of
+    /// the session, failing with `LIBSSH2_ERROR_EAGAIN` if nothing came in.
+    pub(crate) fn read_nonblocking(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.nonblocking(|raw| unsafe {
+            raw::libssh2_channel_read_ex(raw, 0, buf.as_mut_ptr() as *mut _, buf.len() as size_t)
+        })
+    }
+
+    /// Writes to the main stream without blocking, whatever the mode of the
+    /// session, failing with `LIBSSH2_ERROR_EAGAIN` if nothing can be sent.
+    pub(crate) fn write_nonblocking(&self, buf: &[u8]) -> Result<usize, Error> {
+        self.nonblocking(|raw| unsafe {
+            raw::libssh2_channel_write_ex(raw, 0, buf.as_ptr() as *mut _, buf.len() as size_t)
+        })
+    }
+
+    /// Makes `call` with the session switched to non-blocking mode, and put
+    /// back in its previous mode before it is unlocked, so that other users
+    /// of the session never see the switch.
+    fn nonblocking<F>(&self, call: F) -> Result<usize, Error>
+    where
+        F: FnOnce(*mut raw::LIBSSH2_CHANNEL) -> isize,
+    {
+        let locked = self.lock();
+        let was_blocking = locked.sess.is_blocking();
+        locked.sess.set_blocking(false);
+        let rc = call(locked.raw);
+        locked.sess.set_blocking(was_blocking);
+        locked.sess.rc(rc as c_int).map(|()| rc as usize)
+    }
+
+    /// Waits for the session socket to be ready in `directions`, for at most
+    /// `timeout`.
+    #[cfg(unix)]
+    pub(crate) fn wait_socket(&self, directions: c_int, timeout: Option<Duration>) {
+        let fd = match self.channel_inner.sess.lock().raw_fd() {
+            Some(fd) => fd,
+            None => return,
+        };
+        let mut events = libc::POLLIN;
+        if directions & raw::LIBSSH2_SESSION_BLOCK_OUTBOUND != 0 {
+            events |= libc::POLLOUT;
+        }
+        let mut fd = libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+        let timeout = match timeout {
+            // Rounded up, so as not to spin until the deadline, and capped
+            // to fit
+            Some(timeout) => {
+                let millis =
+                    timeout.as_millis() + u128::from(timeout.subsec_nanos() % 1_000_000 != 0);
+                cmp::min(millis, 1 << 30) as c_int
+            }
+            None => -1,
+        };
+        unsafe {
+            let _ = libc::poll(&mut fd, 1, timeout);
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn wait_socket(&self, _directions: c_int, timeout: Option<Duration>) {
+        thread::sleep(match timeout {
+            Some(timeout) => cmp::min(timeout, POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        });
+    }
+
     /// Adjust the receive window for a channel by adjustment bytes.
     ///
     /// If the amount to be adjusted is less than the minimum adjustment and
@@ -409,6 +569,43 @@ impl Channel {
         Ok(ret as u64)
     }
 
+    /// Grow the windows of an open channel to at least `local` bytes for
+    /// the data the remote end sends us, and check that it lets us send at
+    /// least `remote` bytes.
+    ///
+    /// Window and packet sizes are best chosen when opening the channel, with
+    /// `Session::channel_open`; past that point, only the receive window can
+    /// be changed, by granting the remote end more room, and the maximum
+    /// packet size is fixed. The window for what we send belongs to the
+    /// remote end, so an error is returned if it granted less than `remote`:
+    /// tuning it is only possible in the server configuration. Windows are
+    /// never shrunk, and 0 leaves either side as it is.
+    pub fn set_window_sizes(&mut self, local: u32, remote: u32) -> Result<(), Error> {
+        let locked = self.lock();
+        let remaining = unsafe {
+            raw::libssh2_channel_window_read_ex(locked.raw, ptr::null_mut(), ptr::null_mut())
+        } as u32;
+        if local > remaining {
+            let rc = unsafe {
+                raw::libssh2_channel_receive_window_adjust2(
+                    locked.raw,
+                    (local - remaining) as c_ulong,
+                    1,
+                    ptr::null_mut(),
+                )
+            };
+            locked.sess.rc(rc)?;
+        }
+        let granted = unsafe { raw::libssh2_channel_window_write_ex(locked.raw, ptr::null_mut()) };
+        if (granted as u32) < remote {
+            return Err(Error::new(
+                raw::LIBSSH2_ERROR_INVAL,
+                "the remote end granted a smaller send window",
+            ));
+        }
+        Ok(())
+    }
+
     /// Artificially limit the number of bytes that will be read from this
     /// channel. Hack intended for use by scp_recv only.
     #[doc(hidden)]
@@ -467,6 +664,195 @@ impl Channel {
         let locked = self.lock();
         unsafe { locked.sess.rc(raw::libssh2_channel_wait_closed(locked.raw)) }
     }
+
+    /// Execute a command, and collect its output and exit status once it
+    /// has completed.
+    ///
+    /// Standard output and standard error are read alternately, so that a
+    /// command writing a lot to either of them cannot stall. If `deadline`
+    /// passes before the command completes, the channel is closed and an
+    /// error with code `LIBSSH2_ERROR_TIMEOUT` is returned.
+    ///
+    /// The session is switched to non-blocking mode while this runs, and
+    /// checked for progress every few milliseconds; its previous mode is
+    /// restored before returning.
+    pub fn exec_capture(
+        &mut self,
+        command: &str,
+        deadline: Option<Instant>,
+    ) -> Result<CommandOutput, Error> {
+        self.exec(command)?;
+        let was_blocking = {
+            let locked = self.lock();
+            let was_blocking = locked.sess.is_blocking();
+            locked.sess.set_blocking(false);
+            was_blocking
+        };
+        let res = self.capture(deadline);
+        let locked = self.lock();
+        if res.is_err() {
+            // Best effort: the channel is unusable either way
+            let _ = unsafe { raw::libssh2_channel_close(locked.raw) };
+        }
+        locked.sess.set_blocking(was_blocking);
+        res
+    }
+
+    fn capture(&mut self, deadline: Option<Instant>) -> Result<CommandOutput, Error> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut buf = vec![0; 32 * 1024];
+        loop {
+            let progress = self.read_into(0, &mut buf, &mut stdout)?
+                | self.read_into(::EXTENDED_DATA_STDERR, &mut buf, &mut stderr)?;
+            if progress {
+                continue;
+            }
+            if self.eof() {
+                break;
+            }
+            wait_until(deadline)?;
+        }
+        retry_until(deadline, || self.close())?;
+        retry_until(deadline, || self.wait_close())?;
+        Ok(CommandOutput {
+            stdout,
+            stderr,
+            exit_status: self.exit_status()?,
+        })
+    }
+
+    /// Iterate over the lines written to standard output, as they arrive.
+    ///
+    /// Lines are yielded without their `\n` or `\r\n` terminator, and a last
+    /// line without one is yielded once EOF is reached. Lines which aren't
+    /// valid UTF-8 are reported as `ErrorKind::InvalidData` errors.
+    ///
+    /// Errors don't end the iteration: whatever was read of the current line
+    /// is kept, so that after a timeout of the session, or an error with
+    /// `ErrorKind::WouldBlock` in non-blocking mode, `next` can be called
+    /// again to carry on. Standard error must be drained separately, by the
+    /// caller, if the command may write a lot to it.
+    pub fn lines<'a>(&'a mut self) -> impl Iterator<Item = io::Result<String>> + 'a {
+        Lines {
+            channel: self,
+            pending: Vec::new(),
+            buf: vec![0; 4096],
+            eof: false,
+        }
+    }
+
+    /// Appends to `out` what can be read from a stream without blocking,
+    /// returning whether anything was.
+    fn read_into(&self, id: i32, buf: &mut [u8], out: &mut Vec<u8>) -> Result<bool, Error> {
+        let locked = self.lock();
+        let rc = unsafe {
+            raw::libssh2_channel_read_ex(
+                locked.raw,
+                id as c_int,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as size_t,
+            )
+        };
+        if rc as c_int == raw::LIBSSH2_ERROR_EAGAIN {
+            return Ok(false);
+        }
+        locked.sess.rc(rc as c_int)?;
+        out.extend_from_slice(&buf[..rc as usize]);
+        Ok(rc > 0)
+    }
+}
+
+/// How long to wait before checking again on an operation that would block.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long is left until `deadline`: `Some(None)` without one, `None` once
+/// it has passed.
+pub(crate) fn remaining_until(deadline: Option<Instant>) -> Option<Option<Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                None
+            } else {
+                Some(Some(deadline - now))
+            }
+        }
+        None => Some(None),
+    }
+}
+
+/// Waits a bit before retrying an operation, unless `deadline` has passed.
+fn wait_until(deadline: Option<Instant>) -> Result<(), Error> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => {
+            return Err(Error::new(
+                raw::LIBSSH2_ERROR_TIMEOUT,
+                "deadline exceeded before the command completed",
+            ))
+        }
+        _ => {}
+    }
+    thread::sleep(POLL_INTERVAL);
+    Ok(())
+}
+
+fn retry_until<F>(deadline: Option<Instant>, mut op: F) -> Result<(), Error>
+where
+    F: FnMut() -> Result<(), Error>,
+{
+    loop {
+        match op() {
+            Err(ref e) if e.code() == raw::LIBSSH2_ERROR_EAGAIN => wait_until(deadline)?,
+            res => return res,
+        }
+    }
+}
+
+/// Iterator returned by `Channel::lines`.
+struct Lines<'a> {
+    channel: &'a mut Channel,
+    // Data read past the last line yielded
+    pending: Vec<u8>,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<'a> Lines<'a> {
+    fn take_line(&mut self, end: usize, next: usize) -> io::Result<String> {
+        let rest = self.pending.split_off(next);
+        let mut line = mem::replace(&mut self.pending, rest);
+        line.truncate(end);
+        if line.last() == Some(&b'\r') {
+            let _ = line.pop();
+        }
+        String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        loop {
+            if let Some(i) = self.pending.iter().position(|b| *b == b'\n') {
+                return Some(self.take_line(i, i + 1));
+            }
+            if self.eof {
+                if self.pending.is_empty() {
+                    return None;
+                }
+                let len = self.pending.len();
+                return Some(self.take_line(len, len));
+            }
+            match self.channel.read(&mut self.buf) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.pending.extend_from_slice(&self.buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 }
 
 impl Write for Channel {
@@ -519,14 +905,18 @@ impl Read for Stream {
             }
             None => data,
         };
-        let ret = unsafe {
-            let rc = raw::libssh2_channel_read_ex(
-                locked.raw,
-                locked.id as c_int,
-                data.as_mut_ptr() as *mut _,
-                data.len() as size_t,
-            );
-            locked.sess.rc(rc as c_int).map(|()| rc as usize)
+        let ret = loop {
+            let rc = unsafe {
+                raw::libssh2_channel_read_ex(
+                    locked.raw,
+                    locked.id as c_int,
+                    data.as_mut_ptr() as *mut _,
+                    data.len() as size_t,
+                )
+            };
+            if !locked.sess.interrupted(rc as c_int) {
+                break locked.sess.rc(rc as c_int).map(|()| rc as usize);
+            }
         };
         match ret {
             Ok(n) => {
@@ -543,18 +933,27 @@ impl Read for Stream {
 impl Write for Stream {
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         let locked = self.lock();
-        unsafe {
-            let rc = raw::libssh2_channel_write_ex(
-                locked.raw,
-                locked.id as c_int,
-                data.as_ptr() as *mut _,
-                data.len() as size_t,
-            );
-            locked.sess.rc(rc as c_int).map(|()| rc as usize)
+        loop {
+            let rc = unsafe {
+                raw::libssh2_channel_write_ex(
+                    locked.raw,
+                    locked.id as c_int,
+                    data.as_ptr() as *mut _,
+                    data.len() as size_t,
+                )
+            };
+            if !locked.sess.interrupted(rc as c_int) {
+                return locked
+                    .sess
+                    .rc(rc as c_int)
+                    .map(|()| rc as usize)
+                    .map_err(Into::into);
+            }
         }
-        .map_err(Into::into)
     }
 
+    /// Discards the incoming data of this stream that has not been read
+    /// yet, see `Channel::flush_stream`.
     fn flush(&mut self) -> io::Result<()> {
         let locked = self.lock();
         unsafe {
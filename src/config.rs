@@ -0,0 +1,336 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use util;
+use {raw, Error};
+
+/// The contents of an OpenSSH client configuration file, such as
+/// `~/.ssh/config`.
+///
+/// Only the options needed to reach a host are interpreted: `HostName`,
+/// `Port`, `User`, `IdentityFile` and `ProxyJump`. Other options are
+/// ignored, as are `Match` blocks and `Include` directives. As with `ssh`,
+/// the first value found for an option wins, so more specific `Host` blocks
+/// should come first.
+///
+/// This is only available with the `ssh-config` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::net::TcpStream;
+/// use ssh2::{Session, SshConfig};
+///
+/// let config = SshConfig::from_default_file().unwrap();
+/// let host = config.host("myalias");
+/// let tcp = TcpStream::connect((host.host_name(), host.port())).unwrap();
+/// let mut sess = Session::new().unwrap();
+/// sess.set_tcp_stream(tcp);
+/// sess.handshake().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone)]
+struct Section {
+    // `None` for the options before the first `Host` line, and for `Match`
+    // blocks which never apply
+    patterns: Option<Vec<String>>,
+    always: bool,
+    options: Vec<(String, Vec<String>)>,
+}
+
+/// The settings resolved for a host by `SshConfig::host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostConfig {
+    host_name: String,
+    port: Option<u16>,
+    user: Option<String>,
+    identity_files: Vec<PathBuf>,
+    proxy_jump: Vec<String>,
+}
+
+impl SshConfig {
+    /// Parses the contents of a configuration file.
+    pub fn parse(s: &str) -> Result<SshConfig, Error> {
+        let mut sections = vec![Section {
+            patterns: None,
+            always: true,
+            options: Vec::new(),
+        }];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, args) = split_line(line)
+                .ok_or_else(|| syntax_error(i, "unbalanced quotes or missing argument"))?;
+            let keyword = keyword.to_lowercase();
+            match &keyword[..] {
+                "host" => sections.push(Section {
+                    patterns: Some(args),
+                    always: false,
+                    options: Vec::new(),
+                }),
+                "match" => sections.push(Section {
+                    patterns: None,
+                    always: false,
+                    options: Vec::new(),
+                }),
+                _ => {
+                    if keyword == "port" && args[0].parse::<u16>().is_err() {
+                        return Err(syntax_error(i, "invalid port"));
+                    }
+                    let section = sections.last_mut().expect("never empty");
+                    section.options.push((keyword, args));
+                }
+            }
+        }
+        Ok(SshConfig { sections })
+    }
+
+    /// Reads and parses the configuration file at `path`.
+    pub fn from_file(path: &Path) -> Result<SshConfig, Error> {
+        let s = fs::read_to_string(path).map_err(|e| util::file_error(path, e))?;
+        SshConfig::parse(&s)
+    }
+
+    /// Reads the configuration of the current user, `~/.ssh/config`.
+    ///
+    /// A missing file yields an empty configuration.
+    pub fn from_default_file() -> Result<SshConfig, Error> {
+        let path = match home_dir() {
+            Some(home) => home.join(".ssh").join("config"),
+            None => return Ok(SshConfig::default()),
+        };
+        if path.exists() {
+            SshConfig::from_file(&path)
+        } else {
+            Ok(SshConfig::default())
+        }
+    }
+
+    /// Resolves the settings to use to connect to `host`, which is the name
+    /// given on the command line of `ssh`: it may be an alias.
+    pub fn host(&self, host: &str) -> HostConfig {
+        let mut host_name = None;
+        let mut port = None;
+        let mut user = None;
+        let mut identity_files = Vec::new();
+        let mut proxy_jump = None;
+        for section in self.sections.iter().filter(|s| s.applies_to(host)) {
+            for (keyword, args) in &section.options {
+                match &keyword[..] {
+                    "hostname" if host_name.is_none() => host_name = Some(args[0].clone()),
+                    "port" if port.is_none() => port = args[0].parse().ok(),
+                    "user" if user.is_none() => user = Some(args[0].clone()),
+                    "identityfile" => identity_files.push(args[0].clone()),
+                    "proxyjump" if proxy_jump.is_none() => proxy_jump = Some(args[0].clone()),
+                    _ => {}
+                }
+            }
+        }
+
+        let host_name = match host_name {
+            Some(name) => expand(&name, host, host, port, user.as_ref()),
+            None => host.to_string(),
+        };
+        let identity_files = identity_files
+            .iter()
+            .map(|path| expand(path, host, &host_name, port, user.as_ref()))
+            .map(PathBuf::from)
+            .collect();
+        let proxy_jump = match proxy_jump {
+            Some(ref jumps) if jumps != "none" => {
+                jumps.split(',').map(|s| s.trim().to_string()).collect()
+            }
+            _ => Vec::new(),
+        };
+        HostConfig {
+            host_name,
+            port,
+            user,
+            identity_files,
+            proxy_jump,
+        }
+    }
+}
+
+impl Section {
+    fn applies_to(&self, host: &str) -> bool {
+        let patterns = match self.patterns {
+            Some(ref patterns) => patterns,
+            None => return self.always,
+        };
+        let host = host.to_lowercase();
+        let mut matched = false;
+        for pattern in patterns {
+            let pattern = pattern.to_lowercase();
+            match pattern.as_bytes().split_first() {
+                Some((&b'!', negated)) => {
+                    if glob_match(negated, host.as_bytes()) {
+                        return false;
+                    }
+                }
+                _ => matched |= glob_match(pattern.as_bytes(), host.as_bytes()),
+            }
+        }
+        matched
+    }
+}
+
+impl HostConfig {
+    /// The name or address to connect to, which is the host itself unless
+    /// `HostName` is set.
+    pub fn host_name(&self) -> &str {
+        &self.host_name
+    }
+
+    /// The port to connect to, 22 unless `Port` is set.
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(22)
+    }
+
+    /// The user to log in as, if `User` is set.
+    pub fn user(&self) -> Option<&str> {
+        match self.user {
+            Some(ref user) => Some(&user[..]),
+            None => None,
+        }
+    }
+
+    /// The keys to try, in order, from the `IdentityFile` lines.
+    ///
+    /// `~` and the `%d`, `%h`, `%n`, `%p`, `%r` and `%u` tokens are expanded
+    /// as `ssh` does. When empty, `ssh` would try its default key files.
+    pub fn identity_files(&self) -> &[PathBuf] {
+        &self.identity_files
+    }
+
+    /// The hosts to jump through before reaching this one, from `ProxyJump`.
+    ///
+    /// Each is in the `[user@]host[:port]` form, and should itself be looked
+    /// up in the configuration.
+    pub fn proxy_jump(&self) -> &[String] {
+        &self.proxy_jump
+    }
+}
+
+/// Splits a line into its keyword and arguments, which may be quoted.
+fn split_line(line: &str) -> Option<(&str, Vec<String>)> {
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, rest) = line.split_at(end);
+    let mut rest = rest.trim_start();
+    let mut chars = rest.chars();
+    if chars.next() == Some('=') {
+        rest = chars.as_str().trim_start();
+    }
+
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    for c in rest.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(arg.clone());
+                    arg.clear();
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    if args.is_empty() {
+        None
+    } else {
+        Some((keyword, args))
+    }
+}
+
+/// Matches `name` against a pattern where `*` stands for any sequence of
+/// characters and `?` for any single one.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((&c, rest)) => match name.split_first() {
+            Some((&n, name_rest)) if c == b'?' || c == n => glob_match(rest, name_rest),
+            _ => false,
+        },
+    }
+}
+
+fn expand(
+    s: &str,
+    host: &str,
+    host_name: &str,
+    port: Option<u16>,
+    user: Option<&String>,
+) -> String {
+    let home = home_dir()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let local_user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default();
+    let mut out = String::new();
+    let mut chars = s.chars();
+    if s.starts_with("~/") {
+        out.push_str(&home);
+        let _ = chars.next();
+    }
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some('d') => out.push_str(&home),
+            Some('h') => out.push_str(host_name),
+            Some('n') => out.push_str(host),
+            Some('p') => out.push_str(&port.unwrap_or(22).to_string()),
+            Some('r') => out.push_str(user.unwrap_or(&local_user)),
+            Some('u') => out.push_str(&local_user),
+            Some(c) => {
+                out.push('%');
+                out.push(c);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+fn syntax_error(line: usize, msg: &str) -> Error {
+    Error::with_message(
+        raw::LIBSSH2_ERROR_INVAL,
+        format!("ssh config line {}: {}", line + 1, msg),
+    )
+}
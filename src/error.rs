@@ -81,6 +81,13 @@ impl Error {
         }
     }
 
+    pub(crate) fn with_message(code: libc::c_int, msg: String) -> Error {
+        Error {
+            code,
+            msg: Cow::Owned(msg),
+        }
+    }
+
     /// Generate an error that represents EOF
     pub fn eof() -> Error {
         Error::new(raw::LIBSSH2_ERROR_CHANNEL_EOF_SENT, "end of file")
@@ -91,14 +98,6 @@ impl Error {
         Error::new(libc::c_int::min_value(), "no other error listed")
     }
 
-    pub(crate) fn rc(rc: libc::c_int) -> Result<(), Error> {
-        if rc == 0 {
-            Ok(())
-        } else {
-            Err(Self::from_errno(rc))
-        }
-    }
-
     /// Construct an error from an error code from libssh2
     pub fn from_errno(code: libc::c_int) -> Error {
         let msg = match code {
@@ -182,6 +181,23 @@ impl Error {
     pub fn code(&self) -> libc::c_int {
         self.code
     }
+
+    /// Returns whether this error means the connection to the server is gone,
+    /// in which case only establishing a new session can help.
+    ///
+    /// This covers failures to use the socket as well as the SFTP statuses
+    /// reporting a lost connection.
+    pub fn is_connection_lost(&self) -> bool {
+        [
+            raw::LIBSSH2_ERROR_SOCKET_SEND,
+            raw::LIBSSH2_ERROR_SOCKET_RECV,
+            raw::LIBSSH2_ERROR_SOCKET_DISCONNECT,
+            raw::LIBSSH2_ERROR_BAD_SOCKET,
+            raw::LIBSSH2_FX_NO_CONNECTION,
+            raw::LIBSSH2_FX_CONNECTION_LOST,
+        ]
+        .contains(&self.code)
+    }
 }
 
 impl From<Error> for io::Error {
@@ -189,6 +205,9 @@ impl From<Error> for io::Error {
         let kind = match err.code {
             raw::LIBSSH2_ERROR_EAGAIN => io::ErrorKind::WouldBlock,
             raw::LIBSSH2_ERROR_TIMEOUT => io::ErrorKind::TimedOut,
+            raw::LIBSSH2_FX_NO_SUCH_FILE | raw::LIBSSH2_FX_NO_SUCH_PATH => io::ErrorKind::NotFound,
+            raw::LIBSSH2_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+            raw::LIBSSH2_FX_FILE_ALREADY_EXISTS => io::ErrorKind::AlreadyExists,
             _ => io::ErrorKind::Other,
         };
         io::Error::new(kind, err.msg)
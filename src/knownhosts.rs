@@ -1,12 +1,20 @@
 use libc::{c_int, size_t};
 use parking_lot::{Mutex, MutexGuard};
-use std::ffi::CString;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io::ErrorKind;
 use std::path::Path;
+use std::process;
+use std::ptr;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use util;
-use {raw, CheckResult, Error, KnownHostFileKind, SessionInner};
+use {raw, CheckResult, Error, KnownHostFileKind, KnownHostKeyFormat, SessionInner};
+
+/// Numbers the temporary files written by `add_and_write`.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 /// A set of known hosts which can be used to verify the identity of a remote
 /// server.
@@ -54,6 +62,8 @@ pub struct KnownHosts {
 pub struct Host {
     name: Option<String>,
     key: String,
+    key_format: KnownHostKeyFormat,
+    hashed: bool,
 }
 
 impl KnownHosts {
@@ -85,6 +95,12 @@ impl KnownHosts {
     }
 
     /// Read a line as if it were from a known hosts file.
+    ///
+    /// Like `read_file`, this adds to the hosts already known, so a system
+    /// wide file can be read and then completed with entries shipped inline.
+    /// Entries are not deduplicated: a host appearing twice keeps both
+    /// entries, `check` reports a match if any of them has the key, and
+    /// `write_file` writes them all out again.
     pub fn read_str(&mut self, s: &str, kind: KnownHostFileKind) -> Result<(), Error> {
         let sess = self.sess.lock();
         sess.rc(unsafe {
@@ -142,6 +158,9 @@ impl KnownHosts {
     }
 
     /// Create an iterator over all of the known hosts in this structure.
+    ///
+    /// The entries are returned in the order they were added, which for a
+    /// file read with `read_file` is the order of its lines.
     pub fn iter(&self) -> Result<Vec<Host>, Error> {
         self.hosts()
     }
@@ -204,6 +223,46 @@ impl KnownHosts {
         }
     }
 
+    /// Delete all the entries for `hostname`, returning how many there were.
+    ///
+    /// Hashed entries are matched as well. Entries are selected the same way
+    /// as `check_port` finds them when `port` is given, so that this also
+    /// removes the entries without a port; otherwise only those are removed,
+    /// as with `check`. This is meant for replacing the key of a host: its
+    /// new key can be added once the old entries are gone.
+    pub fn remove_host(&mut self, hostname: &str, port: Option<u16>) -> Result<usize, Error> {
+        let host = CString::new(hostname)?;
+        let port = port.map(c_int::from).unwrap_or(-1);
+        // No actual key is a single zero byte, so that every entry for the
+        // host is reported as a mismatch along with the entry itself.
+        let key = [0u8];
+        let flags = raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW;
+        let sess = self.sess.lock();
+        let mut removed = 0;
+        loop {
+            let mut entry = ptr::null_mut();
+            let rc = unsafe {
+                raw::libssh2_knownhost_checkp(
+                    self.raw,
+                    host.as_ptr(),
+                    port,
+                    key.as_ptr() as *const _,
+                    key.len() as size_t,
+                    flags,
+                    &mut entry,
+                )
+            };
+            match rc {
+                raw::LIBSSH2_KNOWNHOST_CHECK_MATCH | raw::LIBSSH2_KNOWNHOST_CHECK_MISMATCH => {
+                    sess.rc(unsafe { raw::libssh2_knownhost_del(self.raw, entry) })?;
+                    removed += 1;
+                }
+                raw::LIBSSH2_KNOWNHOST_CHECK_NOTFOUND => return Ok(removed),
+                _ => return Err(sess.last_error().unwrap_or_else(Error::unknown)),
+            }
+        }
+    }
+
     /// Checks a host and its associated key against the collection of known
     /// hosts, and returns info back about the (partially) matched entry.
     ///
@@ -218,9 +277,26 @@ impl KnownHosts {
         self.check_port_(host, port as i32, key)
     }
 
+    /// Checks the key a server presented against the known hosts, taking the
+    /// type of the key into account.
+    ///
+    /// `key` is the raw key as returned by `Session::host_key`. Unlike with
+    /// `check_port`, entries for the host with a key of another type are
+    /// ignored: a server that also has e.g. an ed25519 key next to the RSA
+    /// key on record is reported as `NotFound` rather than as a `Mismatch`,
+    /// so that `Mismatch` only ever means the key of that type changed.
+    pub fn check_host(&self, host: &str, port: u16, key: &[u8]) -> CheckResult {
+        self.check_(host, port as i32, key, key_type_flag(key))
+    }
+
     fn check_port_(&self, host: &str, port: i32, key: &[u8]) -> CheckResult {
+        self.check_(host, port, key, 0)
+    }
+
+    fn check_(&self, host: &str, port: i32, key: &[u8], key_type: c_int) -> CheckResult {
         let host = CString::new(host).unwrap();
-        let flags = raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW;
+        let flags =
+            raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW | key_type;
         unsafe {
             let rc = raw::libssh2_knownhost_checkp(
                 self.raw,
@@ -249,7 +325,8 @@ impl KnownHosts {
     /// the host name and a colon separating the host part from the port number.
     /// For example: `"[host.example.com]:222"`.
     ///
-    /// The key provided must be the raw key for the host.
+    /// The key provided must be the raw key for the host. An empty `comment`
+    /// means the entry has no comment.
     pub fn add(
         &mut self,
         host: &str,
@@ -260,6 +337,14 @@ impl KnownHosts {
         let host = CString::new(host)?;
         let flags =
             raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW | (fmt as c_int);
+        // The pointer of an empty `&str` is dangling, and libssh2 (seen with
+        // 1.11.1) reads through the comment pointer even when its length is
+        // 0, which segfaults; a null pointer means no comment.
+        let comment_ptr = if comment.is_empty() {
+            ptr::null()
+        } else {
+            comment.as_ptr() as *const _
+        };
         let sess = self.sess.lock();
         unsafe {
             let rc = raw::libssh2_knownhost_addc(
@@ -268,7 +353,7 @@ impl KnownHosts {
                 0 as *mut _,
                 key.as_ptr() as *mut _,
                 key.len() as size_t,
-                comment.as_ptr() as *const _,
+                comment_ptr,
                 comment.len() as size_t,
                 flags,
                 0 as *mut _,
@@ -276,6 +361,82 @@ impl KnownHosts {
             sess.rc(rc)
         }
     }
+
+    /// Adds a known host like `add`, then saves the whole collection to
+    /// `file` like `write_file`, in the OpenSSH format.
+    ///
+    /// The file is replaced atomically: the hosts are written to a temporary
+    /// file next to it, which is synced to disk and renamed over `file`, so
+    /// that a crash leaves either the old or the new contents behind. The
+    /// permissions of an existing `file` are kept.
+    ///
+    /// As the file is rewritten from this collection, it should have been
+    /// loaded with `read_file` beforehand so that no entries are lost.
+    pub fn add_and_write(
+        &mut self,
+        file: &Path,
+        host: &str,
+        key: &[u8],
+        key_type: KnownHostKeyFormat,
+        comment: &str,
+    ) -> Result<(), Error> {
+        self.add(host, key, comment, key_type)?;
+
+        // The name is unique to this call, and `create_new` makes sure that
+        // a file left behind by another process isn't reused.
+        let tmp = loop {
+            let mut name = OsString::from(".");
+            name.push(file.file_name().unwrap_or_else(|| "known_hosts".as_ref()));
+            let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            name.push(format!(".{}.{}.tmp", process::id(), n));
+            let tmp = file.with_file_name(name);
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp)
+            {
+                Ok(_) => break tmp,
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(util::file_error(&tmp, e)),
+            }
+        };
+        let res = self
+            .write_file(&tmp, KnownHostFileKind::OpenSSH)
+            .and_then(|()| {
+                let synced = fs::File::open(&tmp).and_then(|f| f.sync_all());
+                synced.map_err(|e| util::file_error(&tmp, e))?;
+                if let Ok(meta) = fs::metadata(file) {
+                    fs::set_permissions(&tmp, meta.permissions())
+                        .map_err(|e| util::file_error(&tmp, e))?;
+                }
+                fs::rename(&tmp, file).map_err(|e| util::file_error(file, e))
+            });
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res
+    }
+}
+
+/// The `LIBSSH2_KNOWNHOST_KEY_*` flag matching the algorithm name found at
+/// the start of a raw public key, or 0 if it isn't recognized.
+fn key_type_flag(key: &[u8]) -> c_int {
+    if key.len() < 4 {
+        return 0;
+    }
+    let len = ((key[0] as usize) << 24)
+        | ((key[1] as usize) << 16)
+        | ((key[2] as usize) << 8)
+        | key[3] as usize;
+    match key.get(4..4 + len) {
+        Some(b"ssh-rsa") => raw::LIBSSH2_KNOWNHOST_KEY_SSHRSA,
+        Some(b"ssh-dss") => raw::LIBSSH2_KNOWNHOST_KEY_SSHDSS,
+        Some(b"ecdsa-sha2-nistp256") => raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_256,
+        Some(b"ecdsa-sha2-nistp384") => raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_384,
+        Some(b"ecdsa-sha2-nistp521") => raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_521,
+        Some(b"ssh-ed25519") => raw::LIBSSH2_KNOWNHOST_KEY_ED25519,
+        _ => 0,
+    }
 }
 
 impl Drop for KnownHosts {
@@ -287,19 +448,59 @@ impl Drop for KnownHosts {
 
 impl Host {
     /// This is `None` if no plain text host name exists.
+    ///
+    /// That is the case of hashed entries, see `is_hashed`.
     pub fn name(&self) -> Option<&str> {
         self.name.as_ref().map(String::as_str)
     }
 
+    /// Returns whether the host name of this entry is stored hashed.
+    ///
+    /// libssh2 doesn't hand out the salt and hash of such entries; the
+    /// `|1|salt|hash` form can be obtained with `KnownHosts::write_string`.
+    pub fn is_hashed(&self) -> bool {
+        self.hashed
+    }
+
     /// Returns the key in base64/printable format
     pub fn key(&self) -> &str {
         &self.key
     }
 
+    /// Returns the raw key, decoded from `key`.
+    ///
+    /// This is `None` if the stored key isn't valid base64.
+    pub fn key_bytes(&self) -> Option<Vec<u8>> {
+        util::base64_decode(&self.key)
+    }
+
+    /// Returns the type of the key.
+    pub fn key_format(&self) -> KnownHostKeyFormat {
+        self.key_format
+    }
+
     unsafe fn from_raw(raw: *mut raw::libssh2_knownhost) -> Self {
         let name = ::opt_bytes(&raw, (*raw).name).and_then(|s| String::from_utf8(s.to_vec()).ok());
         let key = ::opt_bytes(&raw, (*raw).key).unwrap();
         let key = String::from_utf8(key.to_vec()).unwrap();
-        Self { name, key }
+        let typemask = (*raw).typemask;
+        let key_format = match typemask & raw::LIBSSH2_KNOWNHOST_KEY_MASK {
+            raw::LIBSSH2_KNOWNHOST_KEY_RSA1 => KnownHostKeyFormat::Rsa1,
+            raw::LIBSSH2_KNOWNHOST_KEY_SSHRSA => KnownHostKeyFormat::SshRsa,
+            raw::LIBSSH2_KNOWNHOST_KEY_SSHDSS => KnownHostKeyFormat::SshDss,
+            raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_256 => KnownHostKeyFormat::Ecdsa256,
+            raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_384 => KnownHostKeyFormat::Ecdsa384,
+            raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_521 => KnownHostKeyFormat::Ecdsa521,
+            raw::LIBSSH2_KNOWNHOST_KEY_ED25519 => KnownHostKeyFormat::Ed255219,
+            _ => KnownHostKeyFormat::Unknown,
+        };
+        let hashed =
+            typemask & raw::LIBSSH2_KNOWNHOST_TYPE_MASK == raw::LIBSSH2_KNOWNHOST_TYPE_SHA1;
+        Self {
+            name,
+            key,
+            key_format,
+            hashed,
+        }
     }
 }
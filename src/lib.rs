@@ -133,23 +133,38 @@
 #![deny(missing_docs, unused_results)]
 #![cfg_attr(test, deny(warnings))]
 
+extern crate filetime;
 extern crate libc;
 extern crate libssh2_sys as raw;
 #[macro_use]
 extern crate bitflags;
 extern crate parking_lot;
+#[cfg(all(unix, feature = "tokio"))]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 use std::ffi::CStr;
+use std::fmt;
 
 pub use agent::{Agent, PublicKey};
-pub use channel::{Channel, ExitSignal, ReadWindow, Stream, WriteWindow};
+pub use archive::ArchiveReader;
+#[cfg(all(unix, feature = "tokio"))]
+pub use async_file::AsyncSftpFile;
+pub use channel::{Channel, CommandOutput, ExitSignal, ReadWindow, Stream, WriteWindow};
+#[cfg(feature = "ssh-config")]
+pub use config::{HostConfig, SshConfig};
 pub use error::Error;
 pub use knownhosts::{Host, KnownHosts};
 pub use listener::Listener;
+pub use mirror::Mirror;
 use session::SessionInner;
 pub use session::{BlockDirections, KeyboardInteractivePrompt, Prompt, ScpFileStat, Session};
-pub use sftp::{File, FileStat, FileType, OpenType};
-pub use sftp::{OpenFlags, RenameFlags, Sftp};
+pub use session::{NegotiatedAlgorithms, SessionConfig, TraceFlags};
+pub use sftp::{File, FileStat, FileType, FsStat, Metadata, OpenType};
+pub use sftp::{OpenFlags, ReadFile, RenameFlags, Sftp, SftpPool, StatCache, WriteFile};
+pub use shell::{shell_join, shell_quote};
+pub use walk::Walk;
 pub use DisconnectCode::{AuthCancelledByUser, TooManyConnections};
 pub use DisconnectCode::{ByApplication, ConnectionLost, HostKeyNotVerifiable};
 pub use DisconnectCode::{CompressionError, KeyExchangeFailed, MacError, Reserved};
@@ -157,14 +172,75 @@ pub use DisconnectCode::{HostNotAllowedToConnect, ProtocolError};
 pub use DisconnectCode::{IllegalUserName, NoMoreAuthMethodsAvailable};
 pub use DisconnectCode::{ProtocolVersionNotSupported, ServiceNotAvailable};
 
+/// Enters a debug-level span until the end of the enclosing block; compiled
+/// out entirely unless the `tracing` feature is enabled.
+#[cfg(feature = "tracing")]
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        let _span = ::tracing::debug_span!($($args)*).entered();
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_span {
+    ($($args:tt)*) => {};
+}
+
+/// Enters a span like `trace_span!` for a read or write of `len` bytes,
+/// bound to `$span` so that `trace_io_result!` can record the outcome.
+#[cfg(feature = "tracing")]
+macro_rules! trace_io_span {
+    ($span:ident, $name:expr, $len:expr) => {
+        let $span = ::tracing::debug_span!(
+            $name,
+            len = $len,
+            n = ::tracing::field::Empty,
+            error = ::tracing::field::Empty
+        )
+        .entered();
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_io_span {
+    ($($args:tt)*) => {};
+}
+
+/// Records the number of bytes actually transferred, or the error, on a
+/// span entered with `trace_io_span!`.
+#[cfg(feature = "tracing")]
+macro_rules! trace_io_result {
+    ($span:ident, $res:expr) => {
+        match $res {
+            Ok(ref n) => {
+                let _ = $span.record("n", n);
+            }
+            Err(ref e) => {
+                let _ = $span.record("error", &::tracing::field::display(e));
+            }
+        }
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_io_result {
+    ($($args:tt)*) => {};
+}
+
 mod agent;
+mod archive;
+#[cfg(all(unix, feature = "tokio"))]
+mod async_file;
 mod channel;
+#[cfg(feature = "ssh-config")]
+mod config;
 mod error;
 mod knownhosts;
 mod listener;
+mod mirror;
 mod session;
 mod sftp;
+mod shell;
+mod tunnel;
 mod util;
+mod walk;
 
 /// Initialize the libssh2 library.
 ///
@@ -201,18 +277,41 @@ pub enum DisconnectCode {
     IllegalUserName = raw::SSH_DISCONNECT_ILLEGAL_USER_NAME as isize,
 }
 
-#[allow(missing_docs)]
-#[derive(Copy, Clone, Debug)]
+/// The algorithm of a host key, as returned by `Session::host_key`.
+///
+/// This displays the way OpenSSH names key types in its messages, e.g.
+/// `ED25519` or `ECDSA`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum HostKeyType {
+    /// A key type libssh2 doesn't know about
     Unknown = raw::LIBSSH2_HOSTKEY_TYPE_UNKNOWN as isize,
+    /// `ssh-rsa`
     Rsa = raw::LIBSSH2_HOSTKEY_TYPE_RSA as isize,
+    /// `ssh-dss`
     Dss = raw::LIBSSH2_HOSTKEY_TYPE_DSS as isize,
+    /// `ecdsa-sha2-nistp256`
     Ecdsa256 = raw::LIBSSH2_HOSTKEY_TYPE_ECDSA_256 as isize,
+    /// `ecdsa-sha2-nistp384`
     Ecdsa384 = raw::LIBSSH2_HOSTKEY_TYPE_ECDSA_384 as isize,
+    /// `ecdsa-sha2-nistp521`
     Ecdsa521 = raw::LIBSSH2_HOSTKEY_TYPE_ECDSA_521 as isize,
+    /// `ssh-ed25519`
     Ed255219 = raw::LIBSSH2_HOSTKEY_TYPE_ED25519 as isize,
 }
 
+impl fmt::Display for HostKeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            HostKeyType::Unknown => "unknown",
+            HostKeyType::Rsa => "RSA",
+            HostKeyType::Dss => "DSA",
+            HostKeyType::Ecdsa256 | HostKeyType::Ecdsa384 | HostKeyType::Ecdsa521 => "ECDSA",
+            HostKeyType::Ed255219 => "ED25519",
+        };
+        f.write_str(name)
+    }
+}
+
 #[allow(missing_docs)]
 #[derive(Copy, Clone)]
 pub enum MethodType {
@@ -251,7 +350,12 @@ pub enum KnownHostFileKind {
 }
 
 /// Possible results of a call to `KnownHosts::check`
-#[derive(Copy, Clone, Debug)]
+///
+/// A `Mismatch` means the server presented a different key than the one on
+/// record, which may be a man in the middle attack and should be treated as
+/// a hard error. A `NotFound` is the case where the user is typically asked
+/// whether to trust the key on first use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CheckResult {
     /// Hosts and keys match
     Match = raw::LIBSSH2_KNOWNHOST_CHECK_MATCH as isize,
@@ -264,7 +368,7 @@ pub enum CheckResult {
 }
 
 #[allow(missing_docs)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum KnownHostKeyFormat {
     Unknown = raw::LIBSSH2_KNOWNHOST_KEY_UNKNOWN as isize,
     Rsa1 = raw::LIBSSH2_KNOWNHOST_KEY_RSA1 as isize,
@@ -1,5 +1,8 @@
 use parking_lot::Mutex;
+use std::ptr;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use {raw, Channel, Error, SessionInner};
 
 /// A listener represents a forwarding port from the remote server.
@@ -28,6 +31,65 @@ impl Listener {
         }
     }
 
+    /// Like `accept`, but gives up with a `LIBSSH2_ERROR_TIMEOUT` error if no
+    /// connection comes in within `timeout`.
+    ///
+    /// The session is switched to non-blocking mode while waiting, and the
+    /// lock on it is released between attempts so that other channels can
+    /// be used meanwhile.
+    ///
+    /// libssh2 discards the address of the peer which connected to the
+    /// forwarded port, sent by the server when opening the channel, so it
+    /// can't be returned here.
+    pub fn accept_timeout(&mut self, timeout: Duration) -> Result<Channel, Error> {
+        let deadline = Instant::now() + timeout;
+        let was_blocking = {
+            let sess = self.sess.lock();
+            let was_blocking = sess.is_blocking();
+            sess.set_blocking(false);
+            was_blocking
+        };
+        let res = loop {
+            let res = {
+                let sess = self.sess.lock();
+                let chan = unsafe { raw::libssh2_channel_forward_accept(self.raw) };
+                let err = sess.last_error();
+                Channel::from_raw_opt(chan, err, &self.sess)
+            };
+            match res {
+                Err(ref e) if e.code() == raw::LIBSSH2_ERROR_EAGAIN => {}
+                res => break res,
+            }
+            if Instant::now() >= deadline {
+                break Err(Error::new(
+                    raw::LIBSSH2_ERROR_TIMEOUT,
+                    "no connection was forwarded in time",
+                ));
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        self.sess.lock().set_blocking(was_blocking);
+        res
+    }
+
+    /// Ask the server to stop listening on the forwarded port.
+    ///
+    /// libssh2 doesn't ask for a reply to the request, so success only means
+    /// that it was sent: the server may still accept connections on the port
+    /// for a moment, and a refusal goes unnoticed. Dropping a listener sends
+    /// the same request but ignores failures. Connections already accepted
+    /// from this listener stay open.
+    pub fn cancel(mut self) -> Result<(), Error> {
+        let sess = self.sess.lock();
+        let rc = unsafe { raw::libssh2_channel_forward_cancel(self.raw) };
+        // libssh2 only frees the listener once the request is sent. On
+        // failure, including EAGAIN, it is kept for `Drop` to try again
+        if rc == 0 {
+            self.raw = ptr::null_mut();
+        }
+        sess.rc(rc)
+    }
+
     pub(crate) fn from_raw_opt(
         raw: *mut raw::LIBSSH2_LISTENER,
         err: Option<Error>,
@@ -46,6 +108,9 @@ impl Listener {
 
 impl Drop for Listener {
     fn drop(&mut self) {
+        if self.raw.is_null() {
+            return;
+        }
         let _sess = self.sess.lock();
         unsafe {
             let _ = raw::libssh2_channel_forward_cancel(self.raw);
@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use sftp::{FileStat, Sftp};
+use {raw, util, Error};
+
+// Leads the output of `Mirror::state`, so that anything else is rejected
+const STATE_HEADER: &[u8] = b"ssh2-mirror 1\n";
+
+type EntryHook = Box<dyn FnMut(&Path, &FileStat) + Send>;
+type ErrorHook = Box<dyn FnMut(&Path, &Error) -> bool + Send>;
+
+/// A one-way copy of a remote directory tree into a local directory.
+///
+/// This is created by `Sftp::mirror`. The remote tree is walked one
+/// directory at a time, and regular files are downloaded when the local
+/// copy is missing or differs in size or modification time, as decided by
+/// `FileStat::needs_update`. Downloaded files get the remote modification
+/// time, so that they are skipped by the next run. Local files missing on
+/// the server are left alone, and remote symlinks and special files are
+/// skipped.
+///
+/// The work is done by `step`, one entry at a time, or by `run`. An
+/// interrupted mirror can be picked up where it stopped by saving `state`
+/// along the way and handing it to `resume` on a new `Mirror` for the same
+/// directories. The directory being walked when the state was saved is
+/// listed again, its files already copied being skipped as up to date.
+///
+/// Paths handed to the hooks are relative to the roots of the mirror.
+pub struct Mirror {
+    sftp: Sftp,
+    remote: PathBuf,
+    local: PathBuf,
+    // Directories still to be walked, the next one last
+    dirs: Vec<PathBuf>,
+    // The directory being walked and its entries not handled yet, next one
+    // first
+    current: Option<PathBuf>,
+    entries: VecDeque<(PathBuf, FileStat)>,
+    on_skip: Option<EntryHook>,
+    on_download: Option<EntryHook>,
+    on_error: Option<ErrorHook>,
+}
+
+impl Mirror {
+    pub(crate) fn new(sftp: Sftp, remote: &Path, local: &Path) -> Mirror {
+        Mirror {
+            sftp,
+            remote: remote.to_path_buf(),
+            local: local.to_path_buf(),
+            dirs: vec![PathBuf::new()],
+            current: None,
+            entries: VecDeque::new(),
+            on_skip: None,
+            on_download: None,
+            on_error: None,
+        }
+    }
+
+    /// Sets the function called with each file that is not downloaded,
+    /// either because the local copy is up to date or because it is not a
+    /// regular file.
+    pub fn on_skip<F>(&mut self, cb: F)
+    where
+        F: FnMut(&Path, &FileStat) + Send + 'static,
+    {
+        self.on_skip = Some(Box::new(cb));
+    }
+
+    /// Sets the function called with each file once it has been downloaded.
+    pub fn on_download<F>(&mut self, cb: F)
+    where
+        F: FnMut(&Path, &FileStat) + Send + 'static,
+    {
+        self.on_download = Some(Box::new(cb));
+    }
+
+    /// Sets the function called when an entry can't be mirrored, with the
+    /// file or directory at fault.
+    ///
+    /// When it returns true, the entry is given up on and the mirror goes
+    /// on with the next one. Otherwise, as when no such function is set,
+    /// the error is returned by `step` or `run`, and the entry is tried
+    /// again by the next call.
+    pub fn on_error<F>(&mut self, cb: F)
+    where
+        F: FnMut(&Path, &Error) -> bool + Send + 'static,
+    {
+        self.on_error = Some(Box::new(cb));
+    }
+
+    /// Mirrors the next entry, listing the next directory first if all the
+    /// entries of the previous one were handled.
+    ///
+    /// Returns false, doing nothing, once the whole tree has been mirrored.
+    pub fn step(&mut self) -> Result<bool, Error> {
+        let (name, stat) = match self.entries.front().cloned() {
+            Some(entry) => entry,
+            None => return self.next_dir(),
+        };
+        if let Err(err) = self.mirror_entry(&name, &stat) {
+            if !self.skip_error(&name, &err) {
+                return Err(err);
+            }
+        }
+        let _ = self.entries.pop_front();
+        Ok(true)
+    }
+
+    /// Mirrors the rest of the tree.
+    pub fn run(&mut self) -> Result<(), Error> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Whether the whole tree has been mirrored.
+    pub fn is_finished(&self) -> bool {
+        self.dirs.is_empty() && self.entries.is_empty()
+    }
+
+    /// Returns the progress made so far, to be handed to `resume`.
+    ///
+    /// This is an opaque sequence of bytes, which can be stored as is, e.g.
+    /// in a file written after every few calls to `step`.
+    pub fn state(&self) -> Result<Vec<u8>, Error> {
+        let mut state = STATE_HEADER.to_vec();
+        for dir in self.dirs.iter().chain(self.current.iter()) {
+            state.extend_from_slice(&util::path2bytes(dir)?);
+            state.push(0);
+        }
+        Ok(state)
+    }
+
+    /// Picks up the mirror from the progress returned by `state`, in place
+    /// of anything done by this `Mirror` so far.
+    ///
+    /// The state must come from a mirror of the same directories. The
+    /// hooks are left as they are.
+    pub fn resume(&mut self, state: &[u8]) -> Result<(), Error> {
+        let invalid = || Error::new(raw::LIBSSH2_ERROR_INVAL, "invalid mirror state");
+        if !state.starts_with(STATE_HEADER) {
+            return Err(invalid());
+        }
+        let records = &state[STATE_HEADER.len()..];
+        let mut dirs = Vec::new();
+        if let Some((&last, records)) = records.split_last() {
+            if last != 0 {
+                return Err(invalid());
+            }
+            for record in records.split(|&b| b == 0) {
+                dirs.push(bytes2path(record).ok_or_else(invalid)?);
+            }
+        }
+        self.dirs = dirs;
+        self.current = None;
+        self.entries.clear();
+        Ok(())
+    }
+
+    /// Lists the next directory to walk, creating it locally.
+    fn next_dir(&mut self) -> Result<bool, Error> {
+        self.current = None;
+        let dir = match self.dirs.last() {
+            Some(dir) => dir.clone(),
+            None => return Ok(false),
+        };
+        let local = self.local.join(&dir);
+        let listed = fs::create_dir_all(&local)
+            .map_err(|e| util::file_error(&local, e))
+            .and_then(|()| self.sftp.readdir(&self.remote.join(&dir)));
+        match listed {
+            Ok(mut entries) => {
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                let remote = self.remote.join(&dir);
+                let mut named = VecDeque::with_capacity(entries.len());
+                for (path, stat) in entries {
+                    match util::entry_name(&remote, &path) {
+                        Ok(name) => named.push_back((dir.join(name), stat)),
+                        // A name which would lead out of the directory
+                        Err(err) => {
+                            if !self.skip_error(&dir, &err) {
+                                return Err(err);
+                            }
+                        }
+                    }
+                }
+                self.entries = named;
+                self.current = Some(dir);
+            }
+            Err(err) => {
+                if !self.skip_error(&dir, &err) {
+                    return Err(err);
+                }
+            }
+        }
+        let _ = self.dirs.pop();
+        Ok(true)
+    }
+
+    fn mirror_entry(&mut self, name: &Path, stat: &FileStat) -> Result<(), Error> {
+        let file_type = stat.file_type();
+        if file_type.is_dir() {
+            // Already queued if the directory holding it is walked again
+            // after a resume
+            if !self.dirs.iter().any(|dir| dir == name) {
+                self.dirs.push(name.to_path_buf());
+            }
+            return Ok(());
+        }
+
+        let local = self.local.join(name);
+        let up_to_date = !file_type.is_file()
+            || match fs::metadata(&local) {
+                Ok(meta) => !stat.needs_update(&local_stat(&meta)),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => false,
+                Err(e) => return Err(util::file_error(&local, e)),
+            };
+        if up_to_date {
+            if let Some(ref mut cb) = self.on_skip {
+                cb(name, stat);
+            }
+        } else {
+            self.sftp
+                .download_path_verified(&self.remote.join(name), &local)?;
+            if let Some(ref mut cb) = self.on_download {
+                cb(name, stat);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the error hook chose to go on after `err`.
+    fn skip_error(&mut self, name: &Path, err: &Error) -> bool {
+        match self.on_error {
+            Some(ref mut cb) => cb(name, err),
+            None => false,
+        }
+    }
+}
+
+/// The size and modification time of a local file, to compare with the
+/// remote one.
+fn local_stat(meta: &fs::Metadata) -> FileStat {
+    let stat = FileStat::empty().with_size(meta.len());
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+    match mtime {
+        Some(mtime) => stat.with_mtime(mtime.as_secs()),
+        None => stat,
+    }
+}
+
+#[cfg(unix)]
+fn bytes2path(bytes: &[u8]) -> Option<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::prelude::*;
+    Some(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+#[cfg(windows)]
+fn bytes2path(bytes: &[u8]) -> Option<PathBuf> {
+    use std::str;
+    str::from_utf8(bytes).ok().map(PathBuf::from)
+}
@@ -4,20 +4,75 @@ use libc::{self, c_char, c_int, c_long, c_uint, c_void};
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 use std::borrow::Cow;
 use std::ffi::CString;
+use std::io;
 use std::mem;
+use std::net::TcpStream;
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::io::{AsRawSocket, RawSocket};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::slice;
 use std::str;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use channel;
+use tunnel;
 use util;
 use {raw, ByApplication, DisconnectCode, Error, HostKeyType};
 use {Agent, Channel, HashType, KnownHosts, Listener, MethodType, Sftp};
 
+#[cfg(unix)]
+fn set_socket_keepalive(stream: &TcpStream) -> io::Result<()> {
+    let enable: c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_KEEPALIVE,
+            &enable as *const _ as *const c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn set_socket_keepalive(stream: &TcpStream) -> io::Result<()> {
+    const SOL_SOCKET: c_int = 0xffff;
+    const SO_KEEPALIVE: c_int = 0x0008;
+    extern "system" {
+        fn setsockopt(
+            s: usize,
+            level: c_int,
+            optname: c_int,
+            optval: *const c_char,
+            optlen: c_int,
+        ) -> c_int;
+    }
+
+    let enable: c_int = 1;
+    let rc = unsafe {
+        setsockopt(
+            stream.as_raw_socket() as usize,
+            SOL_SOCKET,
+            SO_KEEPALIVE,
+            &enable as *const _ as *const c_char,
+            mem::size_of::<c_int>() as c_int,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
 /// Called by libssh2 to respond to some number of challenges as part of
 /// keyboard interactive authentication.
 pub trait KeyboardInteractivePrompt {
@@ -72,6 +127,59 @@ pub(crate) struct SessionInner {
     tcp: Option<Box<dyn AsRawFd>>,
     #[cfg(windows)]
     tcp: Option<Box<dyn AsRawSocket>>,
+    // Boxed twice so that libssh2 can be given a thin pointer to it
+    trace_handler: Option<Box<TraceHandler>>,
+    replay: Replay,
+}
+
+type TraceHandler = Box<dyn Fn(&str) + Send>;
+
+/// The settings and credentials `Session::reconnect` applies again.
+#[derive(Clone, Default)]
+struct Replay {
+    banner: Option<String>,
+    allow_sigpipe: Option<bool>,
+    compress: bool,
+    // In the order they were set, so that the last one wins again
+    method_prefs: Vec<(MethodType, String)>,
+    keepalive: Option<(bool, u32)>,
+    // Set by `Session::enable_reconnect`, for `auth` to be filled
+    keep_credentials: bool,
+    auth: Option<Credentials>,
+}
+
+/// The arguments of the last successful authentication.
+#[derive(Clone)]
+enum Credentials {
+    Password {
+        username: String,
+        password: String,
+    },
+    Agent {
+        username: String,
+    },
+    PubkeyFile {
+        username: String,
+        pubkey: Option<PathBuf>,
+        privatekey: PathBuf,
+        passphrase: Option<String>,
+    },
+    #[cfg(unix)]
+    PubkeyMemory {
+        username: String,
+        pubkeydata: Option<String>,
+        privatekeydata: String,
+        passphrase: Option<String>,
+    },
+}
+
+impl Replay {
+    /// Keeps the credentials of a successful authentication, if allowed to.
+    fn remember<F: FnOnce() -> Credentials>(&mut self, credentials: F) {
+        if self.keep_credentials {
+            self.auth = Some(credentials());
+        }
+    }
 }
 
 // The compiler doesn't know that it is Send safe because of the raw
@@ -103,11 +211,62 @@ pub struct Session {
     inner: Arc<Mutex<SessionInner>>,
 }
 
+/// The algorithms negotiated for a session, as returned by
+/// `Session::active_methods`.
+///
+/// "cs" stands for the client to server direction, and "sc" for the server
+/// to client one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiatedAlgorithms {
+    /// The key exchange method, such as `curve25519-sha256`.
+    pub kex: String,
+    /// The host key algorithm, such as `ssh-ed25519`.
+    pub host_key: String,
+    /// The cipher used from client to server, such as `aes256-ctr`.
+    pub crypt_cs: String,
+    /// The cipher used from server to client.
+    pub crypt_sc: String,
+    /// The MAC used from client to server, such as `hmac-sha2-256`.
+    pub mac_cs: String,
+    /// The MAC used from server to client.
+    pub mac_sc: String,
+    /// The compression used from client to server, `none` unless enabled
+    /// with `Session::set_compress`.
+    pub comp_cs: String,
+    /// The compression used from server to client.
+    pub comp_sc: String,
+}
+
 /// Metadata returned about a remote file when received via `scp`.
 pub struct ScpFileStat {
     stat: libc::stat,
 }
 
+bitflags! {
+    /// The kinds of debugging output libssh2 can trace, see
+    /// `Session::set_trace`.
+    pub struct TraceFlags: c_int {
+        /// Reading and writing of packets.
+        const TRANS = raw::LIBSSH2_TRACE_TRANS;
+        /// Key exchange.
+        const KEX = raw::LIBSSH2_TRACE_KEX;
+        /// User authentication.
+        const AUTH = raw::LIBSSH2_TRACE_AUTH;
+        /// Channels.
+        const CONN = raw::LIBSSH2_TRACE_CONN;
+        /// SCP transfers.
+        const SCP = raw::LIBSSH2_TRACE_SCP;
+        /// The SFTP subsystem.
+        const SFTP = raw::LIBSSH2_TRACE_SFTP;
+        /// Errors, as they are recorded.
+        const ERROR = raw::LIBSSH2_TRACE_ERROR;
+        /// The publickey subsystem.
+        const PUBLICKEY = raw::LIBSSH2_TRACE_PUBLICKEY;
+        /// Low-level socket calls.
+        const SOCKET = raw::LIBSSH2_TRACE_SOCKET;
+    }
+}
+
 /// The io direction an application has to wait for in order not to block.
 #[derive(Debug, PartialEq)]
 pub enum BlockDirections {
@@ -121,6 +280,36 @@ pub enum BlockDirections {
     Both,
 }
 
+/// The settings of a `Session`, to be applied before its handshake.
+///
+/// Most settings of a session only take effect if they are made before the
+/// handshake, and setting them afterwards goes unnoticed: this collects
+/// them, and `connect` applies them in the right order, handshaking in
+/// blocking mode and only switching to non-blocking mode after, if asked.
+///
+/// ```no_run
+/// use ssh2::SessionConfig;
+/// use std::net::TcpStream;
+///
+/// let tcp = TcpStream::connect("127.0.0.1:22").unwrap();
+/// let sess = SessionConfig::new()
+///     .compress(true)
+///     .timeout(10_000)
+///     .connect(tcp)
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct SessionConfig {
+    banner: Option<String>,
+    allow_sigpipe: Option<bool>,
+    compress: bool,
+    // In the order they were set, so that the last one wins
+    method_prefs: Vec<(MethodType, String)>,
+    timeout_ms: u32,
+    keepalive: Option<(bool, u32)>,
+    blocking: bool,
+}
+
 impl Session {
     /// Initializes an SSH session object.
     ///
@@ -129,7 +318,8 @@ impl Session {
     /// mode, compression, sigpipe, the banner, etc. To associate this session
     /// with a TCP connection, use the `set_tcp_stream` method pass in an
     /// already-established TCP socket, and then follow up with a call to
-    /// `handshake` to perform the ssh protocol handshake.
+    /// `handshake` to perform the ssh protocol handshake. `SessionConfig`
+    /// does all of this in one go.
     pub fn new() -> Result<Session, Error> {
         ::init();
         unsafe {
@@ -141,6 +331,8 @@ impl Session {
                     inner: Arc::new(Mutex::new(SessionInner {
                         raw: ret,
                         tcp: None,
+                        trace_handler: None,
+                        replay: Replay::default(),
                     })),
                 })
             }
@@ -160,9 +352,16 @@ impl Session {
     /// corresponding to the protocol and libssh2 version will be sent by
     /// default.
     pub fn set_banner(&self, banner: &str) -> Result<(), Error> {
-        let banner = CString::new(banner)?;
-        let inner = self.inner();
-        unsafe { inner.rc(raw::libssh2_session_banner_set(inner.raw, banner.as_ptr())) }
+        let banner_c = CString::new(banner)?;
+        let mut inner = self.inner();
+        unsafe {
+            inner.rc(raw::libssh2_session_banner_set(
+                inner.raw,
+                banner_c.as_ptr(),
+            ))?
+        }
+        inner.replay.banner = Some(banner.to_string());
+        Ok(())
     }
 
     /// Flag indicating whether SIGPIPE signals will be allowed or blocked.
@@ -172,7 +371,7 @@ impl Session {
     /// the library to not attempt to block SIGPIPE from the underlying socket
     /// layer.
     pub fn set_allow_sigpipe(&self, block: bool) {
-        let inner = self.inner();
+        let mut inner = self.inner();
         let res = unsafe {
             inner.rc(raw::libssh2_session_flag(
                 inner.raw,
@@ -181,6 +380,7 @@ impl Session {
             ))
         };
         res.unwrap();
+        inner.replay.allow_sigpipe = Some(block);
     }
 
     /// Flag indicating whether this library will attempt to negotiate
@@ -190,7 +390,7 @@ impl Session {
     /// try to negotiate compression enabling for this connection. By default
     /// libssh2 will not attempt to use compression.
     pub fn set_compress(&self, compress: bool) {
-        let inner = self.inner();
+        let mut inner = self.inner();
         let res = unsafe {
             inner.rc(raw::libssh2_session_flag(
                 inner.raw,
@@ -199,6 +399,7 @@ impl Session {
             ))
         };
         res.unwrap();
+        inner.replay.compress = compress;
     }
 
     /// Set or clear blocking mode on session
@@ -298,14 +499,165 @@ impl Session {
         let _ = inner.tcp.replace(Box::new(stream));
     }
 
+    /// Create a session to `host` and `port`, reached through `jump`, an
+    /// authenticated session to a bastion host, as the `ProxyJump` option of
+    /// OpenSSH does.
+    ///
+    /// A `direct-tcpip` channel to the target is opened on `jump`, and the
+    /// new session is handshaken over it; it still needs to be
+    /// authenticated. A background thread relays the data of the new
+    /// session over the channel until the new session is dropped.
+    ///
+    /// The relay only ever makes non-blocking calls on `jump`, switching it
+    /// to non-blocking mode and back while holding its lock, so `jump` can
+    /// keep being used in either mode, including for other tunnels. A
+    /// blocking call on it holds up the relay until it returns, though.
+    pub fn connect_via(jump: &Session, host: &str, port: u16) -> Result<Session, Error> {
+        let channel = jump.channel_direct_tcpip(host, port, None)?;
+        let socket = tunnel::spawn(jump.clone(), channel).map_err(|e| {
+            Error::with_message(
+                raw::LIBSSH2_ERROR_BAD_SOCKET,
+                format!("failed to set up the tunnel: {}", e),
+            )
+        })?;
+        let mut sess = Session::new()?;
+        sess.set_tcp_stream(socket);
+        sess.handshake()?;
+        Ok(sess)
+    }
+
+    /// Keeps the arguments of the successful password and public key
+    /// authentications which follow, for `reconnect` to authenticate the
+    /// same way.
+    ///
+    /// They are kept in memory, in plain text, for as long as the session
+    /// lives, password and key passphrase included. Without this call,
+    /// nothing about the authentication is kept, and `reconnect` leaves it
+    /// to the caller.
+    pub fn enable_reconnect(&self) {
+        self.inner().replay.keep_credentials = true;
+    }
+
+    /// Connect again over `stream`, after the connection of this session was
+    /// lost, applying the same settings and authenticating the same way.
+    ///
+    /// A new session is set up with the banner, flags, method preferences,
+    /// timeout and keepalive settings of this one, and, once the handshake
+    /// is done, authenticated with the arguments of the last successful
+    /// `userauth_password`, `userauth_agent`, `userauth_pubkey_file` or
+    /// `userauth_pubkey_memory` call made after `enable_reconnect`. Without
+    /// it, or for other kinds of authentication such as keyboard-interactive,
+    /// authenticating again is up to the caller. The session is finally put
+    /// in the same blocking mode.
+    ///
+    /// The server must present the same host key as on the previous
+    /// connection, which was hence already checked: otherwise this fails
+    /// with `LIBSSH2_ERROR_KEY_EXCHANGE_FAILURE`, without authenticating.
+    ///
+    /// On success, this `Session` uses the new connection. Objects created
+    /// from the previous one, including clones of this `Session`, stay bound
+    /// to it and keep failing. On failure, this `Session` is left unchanged.
+    pub fn reconnect(&mut self, stream: TcpStream) -> Result<(), Error> {
+        let (replay, timeout, blocking, host_key) = {
+            let inner = self.inner();
+            let timeout = unsafe { raw::libssh2_session_get_timeout(inner.raw) } as u32;
+            let mut len = 0;
+            let mut kind = 0;
+            let host_key = unsafe {
+                let ptr = raw::libssh2_session_hostkey(inner.raw, &mut len, &mut kind);
+                if ptr.is_null() {
+                    None
+                } else {
+                    Some(slice::from_raw_parts(ptr as *const u8, len).to_vec())
+                }
+            };
+            (inner.replay.clone(), timeout, inner.is_blocking(), host_key)
+        };
+
+        let config = SessionConfig {
+            banner: replay.banner.clone(),
+            allow_sigpipe: replay.allow_sigpipe,
+            compress: replay.compress,
+            method_prefs: replay.method_prefs.clone(),
+            timeout_ms: timeout,
+            keepalive: replay.keepalive,
+            blocking: true,
+        };
+        let sess = config.connect(stream)?;
+        sess.inner().replay.keep_credentials = replay.keep_credentials;
+
+        let new_host_key = sess.host_key().map(|(key, _)| key.to_vec());
+        if host_key.is_some() && new_host_key != host_key {
+            return Err(Error::new(
+                raw::LIBSSH2_ERROR_KEY_EXCHANGE_FAILURE,
+                "the host key differs from the one of the previous connection",
+            ));
+        }
+
+        match replay.auth {
+            Some(Credentials::Password { username, password }) => {
+                sess.userauth_password(&username, &password)?
+            }
+            Some(Credentials::Agent { username }) => sess.userauth_agent(&username)?,
+            Some(Credentials::PubkeyFile {
+                username,
+                pubkey,
+                privatekey,
+                passphrase,
+            }) => sess.userauth_pubkey_file(
+                &username,
+                match pubkey {
+                    Some(ref pubkey) => Some(pubkey.as_path()),
+                    None => None,
+                },
+                &privatekey,
+                passphrase.as_ref().map(|p| &p[..]),
+            )?,
+            #[cfg(unix)]
+            Some(Credentials::PubkeyMemory {
+                username,
+                pubkeydata,
+                privatekeydata,
+                passphrase,
+            }) => sess.userauth_pubkey_memory(
+                &username,
+                pubkeydata.as_ref().map(|p| &p[..]),
+                &privatekeydata,
+                passphrase.as_ref().map(|p| &p[..]),
+            )?,
+            None => {}
+        }
+
+        sess.set_blocking(blocking);
+        *self = sess;
+        Ok(())
+    }
+
+    /// Apply the socket options recommended for SSH to `stream`, before
+    /// handing it over to `set_tcp_stream`.
+    ///
+    /// This disables Nagle's algorithm (`TCP_NODELAY`), which otherwise holds
+    /// back the small packets of interactive sessions and is a common cause
+    /// of lag, and turns on TCP keepalive (`SO_KEEPALIVE`) so that a peer
+    /// which vanished is eventually detected; the keepalive timings are the
+    /// system's. `set_keepalive` offers keepalives at the SSH level instead,
+    /// which also keep idle connections alive through NATs and firewalls.
+    pub fn configure_socket(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        set_socket_keepalive(stream)
+    }
+
     /// Attempt basic password authentication.
     ///
     /// Note that many SSH servers which appear to support ordinary password
     /// authentication actually have it disabled and use Keyboard Interactive
     /// authentication (routed via PAM or another authentication backed)
     /// instead.
+    ///
+    /// After `enable_reconnect`, the password is kept in memory until the
+    /// session is dropped.
     pub fn userauth_password(&self, username: &str, password: &str) -> Result<(), Error> {
-        let inner = self.inner();
+        let mut inner = self.inner();
         inner.rc(unsafe {
             raw::libssh2_userauth_password_ex(
                 inner.raw,
@@ -315,7 +667,12 @@ impl Session {
                 password.len() as c_uint,
                 None,
             )
-        })
+        })?;
+        inner.replay.remember(|| Credentials::Password {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        Ok(())
     }
 
     /// Attempt keyboard interactive authentication.
@@ -452,11 +809,18 @@ impl Session {
                 ))
             }
         };
-        agent.userauth(username, &identity)
+        agent.userauth(username, &identity)?;
+        self.inner().replay.remember(|| Credentials::Agent {
+            username: username.to_string(),
+        });
+        Ok(())
     }
 
     /// Attempt public key authentication using a PEM encoded private key file
     /// stored on disk.
+    ///
+    /// After `enable_reconnect`, the passphrase is kept in memory until the
+    /// session is dropped.
     pub fn userauth_pubkey_file(
         &self,
         username: &str,
@@ -464,6 +828,12 @@ impl Session {
         privatekey: &Path,
         passphrase: Option<&str>,
     ) -> Result<(), Error> {
+        let credentials = || Credentials::PubkeyFile {
+            username: username.to_string(),
+            pubkey: pubkey.map(Path::to_path_buf),
+            privatekey: privatekey.to_path_buf(),
+            passphrase: passphrase.map(str::to_string),
+        };
         let pubkey = match pubkey {
             Some(s) => Some(CString::new(util::path2bytes(s)?)?),
             None => None,
@@ -473,7 +843,7 @@ impl Session {
             Some(s) => Some(CString::new(s)?),
             None => None,
         };
-        let inner = self.inner();
+        let mut inner = self.inner();
         inner.rc(unsafe {
             raw::libssh2_userauth_publickey_fromfile_ex(
                 inner.raw,
@@ -486,7 +856,9 @@ impl Session {
                     .map(|s| s.as_ptr())
                     .unwrap_or(0 as *const _),
             )
-        })
+        })?;
+        inner.replay.remember(credentials);
+        Ok(())
     }
 
     /// Attempt public key authentication using a PEM encoded private key from
@@ -494,6 +866,9 @@ impl Session {
     /// This is available only for `unix` targets, as it relies on openssl.
     /// It is therefore recommended to use `#[cfg(unix)]` or otherwise test for
     /// the `unix` compliation target when using this function.
+    ///
+    /// After `enable_reconnect`, the private key and its passphrase are kept
+    /// in memory until the session is dropped.
     #[cfg(unix)]
     pub fn userauth_pubkey_memory(
         &self,
@@ -502,6 +877,12 @@ impl Session {
         privatekeydata: &str,
         passphrase: Option<&str>,
     ) -> Result<(), Error> {
+        let credentials = || Credentials::PubkeyMemory {
+            username: username.to_string(),
+            pubkeydata: pubkeydata.map(str::to_string),
+            privatekeydata: privatekeydata.to_string(),
+            passphrase: passphrase.map(str::to_string),
+        };
         let (pubkeydata, pubkeydata_len) = match pubkeydata {
             Some(s) => (Some(CString::new(s)?), s.len()),
             None => (None, 0),
@@ -512,7 +893,7 @@ impl Session {
             Some(s) => Some(CString::new(s)?),
             None => None,
         };
-        let inner = self.inner();
+        let mut inner = self.inner();
         inner.rc(unsafe {
             raw::libssh2_userauth_publickey_frommemory(
                 inner.raw,
@@ -530,7 +911,9 @@ impl Session {
                     .map(|s| s.as_ptr())
                     .unwrap_or(0 as *const _),
             )
-        })
+        })?;
+        inner.replay.remember(credentials);
+        Ok(())
     }
 
     // Umm... I wish this were documented in libssh2?
@@ -617,15 +1000,20 @@ impl Session {
     /// will be ignored and not sent to the remote host during protocol
     /// negotiation.
     pub fn method_pref(&self, method_type: MethodType, prefs: &str) -> Result<(), Error> {
-        let prefs = CString::new(prefs)?;
-        let inner = self.inner();
+        let prefs_c = CString::new(prefs)?;
+        let mut inner = self.inner();
         unsafe {
             inner.rc(raw::libssh2_session_method_pref(
                 inner.raw,
                 method_type as c_int,
-                prefs.as_ptr(),
-            ))
+                prefs_c.as_ptr(),
+            ))?
         }
+        inner
+            .replay
+            .method_prefs
+            .push((method_type, prefs.to_string()));
+        Ok(())
     }
 
     /// Return the currently active algorithms.
@@ -640,6 +1028,38 @@ impl Session {
         }
     }
 
+    /// Return all the algorithms negotiated for this session, for instance
+    /// to record them when auditing connections.
+    ///
+    /// Returns `None` until the handshake is done.
+    pub fn active_methods(&self) -> Option<NegotiatedAlgorithms> {
+        let method = |method_type| self.methods(method_type).map(str::to_string);
+        Some(NegotiatedAlgorithms {
+            kex: method(MethodType::Kex)?,
+            host_key: method(MethodType::HostKey)?,
+            crypt_cs: method(MethodType::CryptCs)?,
+            crypt_sc: method(MethodType::CryptSc)?,
+            mac_cs: method(MethodType::MacCs)?,
+            mac_sc: method(MethodType::MacSc)?,
+            comp_cs: method(MethodType::CompCs)?,
+            comp_sc: method(MethodType::CompSc)?,
+        })
+    }
+
+    /// Whether any of the algorithms negotiated for this session is
+    /// considered weak, for instance to warn about servers which only offer
+    /// deprecated ones.
+    ///
+    /// See `NegotiatedAlgorithms::weak_algorithms` for what is flagged, and
+    /// to report the algorithms at fault. Returns false until the handshake
+    /// is done.
+    pub fn is_using_weak_crypto(&self) -> bool {
+        match self.active_methods() {
+            Some(algorithms) => !algorithms.weak_algorithms().is_empty(),
+            None => false,
+        }
+    }
+
     /// Get list of supported algorithms.
     pub fn supported_algs(&self, method_type: MethodType) -> Result<Vec<&'static str>, Error> {
         static STATIC: () = ();
@@ -740,6 +1160,10 @@ impl Session {
     ///
     /// New connections will be queued by the library until accepted by the
     /// `accept` method on the returned `Listener`.
+    ///
+    /// The port the server actually listens on is returned along with the
+    /// listener. It is the one picked by the server when `remote_port` is 0,
+    /// which is how a free port is allocated dynamically.
     pub fn channel_forward_listen(
         &self,
         remote_port: u16,
@@ -882,7 +1306,7 @@ impl Session {
         unsafe { ::opt_bytes(self, raw::libssh2_session_banner_get(inner.raw)) }
     }
 
-    /// Get the remote key.
+    /// Get the remote key, along with its type.
     ///
     /// Returns `None` if something went wrong.
     pub fn host_key(&self) -> Option<(&[u8], HostKeyType)> {
@@ -940,8 +1364,9 @@ impl Session {
     /// I/O, use 0 (the default) to disable keepalives. To avoid some busy-loop
     /// corner-cases, if you specify an interval of 1 it will be treated as 2.
     pub fn set_keepalive(&self, want_reply: bool, interval: u32) {
-        let inner = self.inner();
+        let mut inner = self.inner();
         unsafe { raw::libssh2_keepalive_config(inner.raw, want_reply as c_int, interval as c_uint) }
+        inner.replay.keepalive = Some((want_reply, interval));
     }
 
     /// Send a keepalive message if needed.
@@ -956,6 +1381,65 @@ impl Session {
         Ok(ret as u32)
     }
 
+    /// Wait until any of `channels` can be read from, or has reached EOF, and
+    /// return the index of the first such channel.
+    ///
+    /// This is `Channel::wait_readable` for several channels at once: the
+    /// packets which already arrived are processed, and the socket shared by
+    /// the channels is waited on without keeping the session locked. As with
+    /// `wait_readable`, a `timeout` which expires first isn't an error:
+    /// `None` is returned, rather than a `LIBSSH2_ERROR_TIMEOUT` error which
+    /// callers would have to tell apart from a failure of the session. All
+    /// channels must have been opened on this session.
+    pub fn wait_any(
+        &self,
+        channels: &[&Channel],
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, Error> {
+        if channels.is_empty() || !channels.iter().all(|c| c.belongs_to(&self.inner)) {
+            return Err(Error::new(
+                raw::LIBSSH2_ERROR_BAD_USE,
+                "channels must be given, all from this session",
+            ));
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // Processing the packets for one channel may deliver data to any
+            // other, so only check them once it is done for all
+            let mut directions = 0;
+            for channel in channels {
+                directions = channel.pump()?;
+            }
+            if let Some(i) = channels.iter().position(|c| c.is_readable()) {
+                return Ok(Some(i));
+            }
+            match channel::remaining_until(deadline) {
+                Some(remaining) => channels[0].wait_socket(directions, remaining),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Check that the connection is alive and authenticated, without
+    /// running anything on the remote host.
+    ///
+    /// A session channel is opened and closed right away, which takes a
+    /// round trip to the server: unlike with `keepalive_send`, whose
+    /// messages get no answer libssh2 waits for, a dead connection is
+    /// reported, possibly once the session timeout expires. No command nor
+    /// shell is started on the channel, although servers may log it.
+    pub fn ping(&self) -> Result<(), Error> {
+        if !self.authenticated() {
+            return Err(Error::new(
+                raw::LIBSSH2_ERROR_AUTHENTICATION_FAILED,
+                "the session is not authenticated",
+            ));
+        }
+        let mut channel = self.channel_session()?;
+        channel.close()?;
+        channel.wait_close()
+    }
+
     /// Terminate the transport layer.
     ///
     /// Send a disconnect message to the remote host associated with session,
@@ -999,11 +1483,200 @@ impl Session {
         }
     }
 
+    /// Enables libssh2's debugging output for the given kinds of events,
+    /// disabling it for the others.
+    ///
+    /// The output goes to stderr unless `set_trace_handler` is used. libssh2
+    /// only traces anything when it was built with debugging enabled, which
+    /// isn't the case of the copy bundled by `libssh2-sys`, nor of most
+    /// system packages: this then does nothing.
+    pub fn set_trace(&self, flags: TraceFlags) {
+        let inner = self.inner();
+        unsafe {
+            let _ = raw::libssh2_trace(inner.raw, flags.bits());
+        }
+    }
+
+    /// Sends the output enabled by `set_trace` to `handler`, one trace
+    /// message at a time, instead of stderr.
+    ///
+    /// This allows routing it to a logging framework. The handler is called
+    /// while the session is in use, from within the call being traced: it
+    /// must not use the session, nor any object created from it.
+    pub fn set_trace_handler<F>(&self, handler: F) -> Result<(), Error>
+    where
+        F: Fn(&str) + Send + 'static,
+    {
+        extern "C" fn trace(
+            _sess: *mut raw::LIBSSH2_SESSION,
+            context: *mut c_void,
+            data: *const c_char,
+            length: size_t,
+        ) {
+            use std::panic::{catch_unwind, AssertUnwindSafe};
+            // Panics can't be allowed to unwind into libssh2
+            let _ = catch_unwind(AssertUnwindSafe(|| {
+                let handler = unsafe { &*(context as *const TraceHandler) };
+                let data = unsafe { slice::from_raw_parts(data as *const u8, length) };
+                let message = String::from_utf8_lossy(data);
+                handler(message.trim_end_matches('\n'));
+            }));
+        }
+
+        let mut inner = self.inner();
+        let handler: Box<TraceHandler> = Box::new(Box::new(handler));
+        let context = &*handler as *const TraceHandler as *mut c_void;
+        let rc = unsafe { raw::libssh2_trace_sethandler(inner.raw, context, Some(trace)) };
+        inner.rc(rc)?;
+        // The previous handler, if any, is only dropped once replaced
+        inner.trace_handler = Some(handler);
+        Ok(())
+    }
+
+    /// Returns the most recent error recorded on this session, if any.
+    ///
+    /// The returned `Error` carries both the libssh2 error code and the
+    /// human-readable message libssh2 attached to it, available through
+    /// `code` and `message`. It is the same value as `Error::last_error`
+    /// gives, rather than a `(code, message)` pair, so that it can be
+    /// returned as the error of a failed operation as is.
+    pub fn last_error(&self) -> Option<Error> {
+        self.inner().last_error()
+    }
+
+    /// Repeatedly run `op` until it stops failing with `EAGAIN`.
+    ///
+    /// This is meant for sessions in non-blocking mode where a particular
+    /// operation should be driven to completion. Each time `op` would block,
+    /// `wait` is called with the current `block_directions` and is expected
+    /// to wait for the socket to become ready in those directions (using
+    /// `poll(2)` or an event loop, for instance) before `op` is retried.
+    ///
+    /// Any other error returned by `op` is returned as-is.
+    pub fn poll_until<T, F, W>(&self, mut op: F, mut wait: W) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+        W: FnMut(BlockDirections),
+    {
+        loop {
+            match op() {
+                Err(ref e) if e.code() == raw::LIBSSH2_ERROR_EAGAIN => {
+                    wait(self.block_directions())
+                }
+                res => return res,
+            }
+        }
+    }
+
     fn inner(&self) -> MutexGuard<SessionInner> {
         self.inner.lock()
     }
 }
 
+impl SessionConfig {
+    /// The settings of a new `Session`: no banner nor method preferences,
+    /// no compression, no timeout, no keepalives, and blocking mode.
+    pub fn new() -> SessionConfig {
+        SessionConfig {
+            banner: None,
+            allow_sigpipe: None,
+            compress: false,
+            method_prefs: Vec::new(),
+            timeout_ms: 0,
+            keepalive: None,
+            blocking: true,
+        }
+    }
+
+    /// The banner to send, see `Session::set_banner`.
+    pub fn banner(mut self, banner: &str) -> SessionConfig {
+        self.banner = Some(banner.to_string());
+        self
+    }
+
+    /// Whether to let SIGPIPE through, see `Session::set_allow_sigpipe`.
+    pub fn allow_sigpipe(mut self, allow: bool) -> SessionConfig {
+        self.allow_sigpipe = Some(allow);
+        self
+    }
+
+    /// Whether to negotiate compression, see `Session::set_compress`.
+    pub fn compress(mut self, compress: bool) -> SessionConfig {
+        self.compress = compress;
+        self
+    }
+
+    /// The preferred algorithms of a kind, see `Session::method_pref`.
+    ///
+    /// A preference for a kind that already has one replaces it. Errors
+    /// setting the preferences are only reported by `build` or `connect`.
+    pub fn method_pref(mut self, method_type: MethodType, prefs: &str) -> SessionConfig {
+        self.method_prefs.push((method_type, prefs.to_string()));
+        self
+    }
+
+    /// The timeout of blocking calls in milliseconds, including the
+    /// handshake, see `Session::set_timeout`.
+    pub fn timeout(mut self, timeout_ms: u32) -> SessionConfig {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// How to send keepalive messages, see `Session::set_keepalive`.
+    pub fn keepalive(mut self, want_reply: bool, interval: u32) -> SessionConfig {
+        self.keepalive = Some((want_reply, interval));
+        self
+    }
+
+    /// Whether the session is left in blocking mode once `connect` is
+    /// done, see `Session::set_blocking`.
+    pub fn blocking(mut self, blocking: bool) -> SessionConfig {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Creates a session with these settings, left for the caller to
+    /// associate with a stream and handshake.
+    ///
+    /// This is for streams `connect` doesn't take. The session is in
+    /// blocking mode whatever `blocking` says, to be switched once the
+    /// handshake is done.
+    pub fn build(&self) -> Result<Session, Error> {
+        let sess = Session::new()?;
+        if let Some(ref banner) = self.banner {
+            sess.set_banner(banner)?;
+        }
+        if let Some(allow) = self.allow_sigpipe {
+            sess.set_allow_sigpipe(allow);
+        }
+        sess.set_compress(self.compress);
+        for &(method_type, ref prefs) in &self.method_prefs {
+            sess.method_pref(method_type, prefs)?;
+        }
+        sess.set_timeout(self.timeout_ms);
+        if let Some((want_reply, interval)) = self.keepalive {
+            sess.set_keepalive(want_reply, interval);
+        }
+        Ok(sess)
+    }
+
+    /// Creates a session with these settings over `stream`, and performs
+    /// the handshake, leaving the session ready to be authenticated.
+    pub fn connect(&self, stream: TcpStream) -> Result<Session, Error> {
+        let mut sess = self.build()?;
+        sess.set_tcp_stream(stream);
+        sess.handshake()?;
+        sess.set_blocking(self.blocking);
+        Ok(sess)
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig::new()
+    }
+}
+
 #[cfg(unix)]
 impl AsRawFd for Session {
     fn as_raw_fd(&self) -> RawFd {
@@ -1049,6 +1722,41 @@ impl SessionInner {
     pub fn is_blocking(&self) -> bool {
         unsafe { raw::libssh2_session_get_blocking(self.raw) != 0 }
     }
+
+    /// Whether the call which just returned `rc` on a blocking session only
+    /// failed because a signal interrupted the syscall libssh2 was in, in
+    /// which case it can be made again as `std::io` would.
+    ///
+    /// libssh2 reports `EINTR` as a socket error, or as a timeout when it
+    /// was waiting on the socket, so `errno` is what tells: this must be
+    /// checked before anything else can change it.
+    #[cfg(unix)]
+    pub(crate) fn interrupted(&self, rc: c_int) -> bool {
+        let errno = io::Error::last_os_error().raw_os_error();
+        if errno != Some(libc::EINTR) || !self.is_blocking() {
+            return false;
+        }
+        match rc {
+            raw::LIBSSH2_ERROR_SOCKET_RECV | raw::LIBSSH2_ERROR_SOCKET_SEND => true,
+            // A timeout is genuine when one is set, `errno` may be stale
+            raw::LIBSSH2_ERROR_TIMEOUT => unsafe {
+                raw::libssh2_session_get_timeout(self.raw) == 0
+            },
+            _ => false,
+        }
+    }
+
+    /// Signals don't interrupt syscalls on windows.
+    #[cfg(windows)]
+    pub(crate) fn interrupted(&self, _rc: c_int) -> bool {
+        false
+    }
+
+    /// The socket the session runs over, if one was set.
+    #[cfg(unix)]
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        self.tcp.as_ref().map(|tcp| tcp.as_raw_fd())
+    }
 }
 
 impl Drop for SessionInner {
@@ -1059,6 +1767,57 @@ impl Drop for SessionInner {
     }
 }
 
+impl NegotiatedAlgorithms {
+    /// The negotiated algorithms which are considered weak, in the order of
+    /// the fields, or nothing if all of them are fine.
+    ///
+    /// This flags key exchanges based on SHA-1 or on groups of less than
+    /// 2048 bits, host keys signing with SHA-1 (`ssh-rsa`, whose
+    /// `rsa-sha2-*` variants are fine, and `ssh-dss`), CBC and RC4 ciphers,
+    /// MD5, RIPEMD-160 and truncated SHA-1 MACs, as well as no cipher or
+    /// MAC at all. Plain `hmac-sha1` is not flagged: HMAC doesn't rely on
+    /// the collision resistance SHA-1 lost, and OpenSSH still offers it.
+    /// The list follows the deprecations of OpenSSH and RFC 9142, and may
+    /// grow in later versions.
+    pub fn weak_algorithms(&self) -> Vec<&str> {
+        let fields = [
+            (&self.kex, weak_kex as fn(&str) -> bool),
+            (&self.host_key, weak_host_key),
+            (&self.crypt_cs, weak_cipher),
+            (&self.crypt_sc, weak_cipher),
+            (&self.mac_cs, weak_mac),
+            (&self.mac_sc, weak_mac),
+        ];
+        fields
+            .iter()
+            .filter(|&&(alg, weak)| weak(alg))
+            .map(|&(alg, _)| &alg[..])
+            .collect()
+    }
+}
+
+fn weak_kex(alg: &str) -> bool {
+    alg.ends_with("-sha1") || alg.starts_with("diffie-hellman-group1-")
+}
+
+fn weak_host_key(alg: &str) -> bool {
+    alg == "ssh-rsa" || alg == "ssh-dss"
+}
+
+fn weak_cipher(alg: &str) -> bool {
+    alg == "none"
+        || alg.ends_with("-cbc")
+        || alg.starts_with("arcfour")
+        || alg == "rijndael-cbc@lysator.liu.se"
+}
+
+fn weak_mac(alg: &str) -> bool {
+    alg == "none"
+        || alg.starts_with("hmac-md5")
+        || alg.starts_with("hmac-ripemd160")
+        || alg.starts_with("hmac-sha1-96")
+}
+
 impl ScpFileStat {
     /// Returns the size of the remote file.
     pub fn size(&self) -> u64 {
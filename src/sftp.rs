@@ -1,19 +1,59 @@
+use filetime::{self, FileTime};
 use libc::{c_int, c_long, c_uint, c_ulong, size_t};
 use parking_lot::{Mutex, MutexGuard};
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CStr, OsString};
+use std::fs;
 use std::io::prelude::*;
 use std::io::{self, ErrorKind, SeekFrom};
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use util;
-use {raw, Error, SessionInner};
+use {raw, ArchiveReader, BlockDirections, Error, Mirror, SessionInner, Walk};
 
 /// A handle to a remote filesystem over SFTP.
 ///
 /// Instances are created through the `sftp` method on a `Session`.
+///
+/// Every operation locks the underlying session for its whole duration, so
+/// calls made on the same session from several threads are executed one
+/// after the other. To actually run operations concurrently, open one `Sftp`
+/// per `Session` and spread the work over them, for instance with `SftpPool`.
+///
+/// Cloning an `Sftp` is cheap and yields another handle to the same channel,
+/// which is convenient to hand to several tasks; their operations are still
+/// serialized as described above. The channel is shut down once the last
+/// clone and the last `File` opened through it are dropped; as when a `File`
+/// is dropped, the server is waited for in blocking mode, but never for more
+/// than 10 seconds, or the session timeout if it is shorter.
+///
+/// Holding on to a clone is therefore all it takes to reuse a channel: a
+/// connection pool can store one `Sftp` per session and hand out clones of
+/// it, which callers drop when done without the subsystem being started
+/// again next time. Dropping the stored one, or the `Session`'s last
+/// objects, is what ends it.
+///
+/// The size of the requests sent to the server can't be configured: libssh2
+/// splits reads and writes into requests of at most 30000 bytes, a size
+/// fixed when it is built and small enough for any server, and doesn't
+/// parse the `limits@openssh.com` extension. `read` and `write` calls may
+/// therefore be of any size, larger ones only keeping more requests in
+/// flight at once.
+#[derive(Clone)]
 pub struct Sftp {
     inner: Option<Arc<SftpInnerDropWrapper>>,
+    // Set by `set_default_mode`, for this handle and the clones made after
+    default_mode: i32,
+    // Set by `set_cwd`, likewise
+    cwd: Option<PathBuf>,
 }
 /// This contains an Option so that we're able to disable the Drop hook when dropping manually,
 /// while still dropping all the fields of SftpInner (which we couldn't do with `mem::forget`)
@@ -21,8 +61,19 @@ struct SftpInnerDropWrapper(Option<SftpInner>);
 struct SftpInner {
     raw: *mut raw::LIBSSH2_SFTP,
     sess: Arc<Mutex<SessionInner>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    // The handles of the files opened through this channel, which are set
+    // to null once closed by `Sftp::close_all`
+    handles: Mutex<Vec<Weak<AtomicPtr<raw::LIBSSH2_SFTP_HANDLE>>>>,
+    would_block: WouldBlockHook,
+    // Set by `Sftp::set_transfer_rate`
+    rate_limit: Mutex<Option<u64>>,
 }
 
+/// The callback set with `Sftp::on_would_block`.
+type WouldBlockHook = Mutex<Option<Box<dyn Fn(BlockDirections) + Send + Sync>>>;
+
 // Sftp is both Send and Sync; the compiler can't see it because it
 // is pessimistic about the raw pointer.  We use Arc/Mutex to guard accessing
 // the raw pointer so we are safe for both.
@@ -32,6 +83,57 @@ unsafe impl Sync for Sftp {}
 struct LockedSftp<'sftp> {
     raw: *mut raw::LIBSSH2_SFTP,
     sess: MutexGuard<'sftp, SessionInner>,
+    would_block: &'sftp WouldBlockHook,
+}
+
+impl<'sftp> LockedSftp<'sftp> {
+    /// Like `SessionInner::rc`, but reporting the status code sent by the
+    /// server when the call failed at the SFTP level.
+    fn rc(&self, rc: c_int) -> Result<(), Error> {
+        self.notify(rc);
+        match self.sftp_status(rc) {
+            Some(status) => Err(Error::from_errno(status)),
+            None => self.sess.rc(rc),
+        }
+    }
+
+    /// The status code the server answered with, if `rc` reports that an
+    /// SFTP request was rejected.
+    fn sftp_status(&self, rc: c_int) -> Option<c_int> {
+        if rc == raw::LIBSSH2_ERROR_SFTP_PROTOCOL {
+            Some(unsafe { raw::libssh2_sftp_last_error(self.raw) } as c_int)
+        } else {
+            None
+        }
+    }
+
+    /// Error describing why the last call on this SFTP channel failed.
+    ///
+    /// When libssh2 only reports a generic protocol error, the status code
+    /// the server answered with is used instead, as it is far more telling.
+    fn last_error(&self) -> Error {
+        if let Some(ref err) = self.sess.last_error() {
+            self.notify(err.code());
+        }
+        match self.sess.last_error() {
+            Some(ref err) if err.code() == raw::LIBSSH2_ERROR_SFTP_PROTOCOL => {
+                let status = unsafe { raw::libssh2_sftp_last_error(self.raw) };
+                Error::from_errno(status as c_int)
+            }
+            Some(err) => err,
+            None => Error::unknown(),
+        }
+    }
+
+    /// `SessionInner::rc`, for the calls which report no SFTP status.
+    fn session_rc(&self, rc: c_int) -> Result<(), Error> {
+        self.notify(rc);
+        self.sess.rc(rc)
+    }
+
+    fn notify(&self, rc: c_int) {
+        notify_would_block(&self.sess, self.would_block, rc)
+    }
 }
 
 /// A file handle to an SFTP connection.
@@ -41,12 +143,42 @@ struct LockedSftp<'sftp> {
 ///
 /// Files are created through `open`, `create`, and `open_mode` on an instance
 /// of `Sftp`.
+///
+/// Once a write returns, or the file is closed, the data is only known to
+/// have reached the server, which may still hold it in its own caches. Use
+/// `sync_all` when it must be on stable storage.
 pub struct File {
     inner: Option<FileInner>,
+    read_ahead: usize,
+    // Data fetched ahead of what the caller asked for, and how much of it
+    // was already handed out.
+    ahead: Vec<u8>,
+    ahead_pos: usize,
+    // Copy of the error of the last failed read or write, see `last_error`.
+    last_error: Mutex<Option<Error>>,
 }
+
+/// A file opened for reading only, through `Sftp::open_read`.
+///
+/// Unlike `File`, this only implements `Read` and `Seek`, so that writes
+/// can't be mixed in by mistake. Use `into_inner` for the other operations
+/// of `File`.
+pub struct ReadFile(File);
+
+/// A file opened for writing only, through `Sftp::create_write`.
+///
+/// Unlike `File`, this only implements `Write`: there is no read-ahead
+/// buffer to get out of sync, nor a file pointer to move while writes are
+/// in flight. Use `into_inner` for the other operations of `File`.
+pub struct WriteFile(File);
+
 struct FileInner {
-    raw: *mut raw::LIBSSH2_SFTP_HANDLE,
+    // Only accessed with the session locked
+    raw: Arc<AtomicPtr<raw::LIBSSH2_SFTP_HANDLE>>,
     sftp: Arc<SftpInnerDropWrapper>,
+    path: PathBuf,
+    flags: OpenFlags,
+    open_type: OpenType,
 }
 
 // File is both Send and Sync; the compiler can't see it because it
@@ -57,12 +189,55 @@ unsafe impl Sync for File {}
 
 struct LockedFile<'file> {
     raw: *mut raw::LIBSSH2_SFTP_HANDLE,
+    sftp: *mut raw::LIBSSH2_SFTP,
     sess: MutexGuard<'file, SessionInner>,
+    bytes_read: &'file AtomicU64,
+    bytes_written: &'file AtomicU64,
+    would_block: &'file WouldBlockHook,
+}
+
+impl<'file> LockedFile<'file> {
+    /// Like `LockedSftp::rc`, for calls made on a handle.
+    fn rc(&self, rc: c_int) -> Result<(), Error> {
+        self.notify(rc);
+        if rc == raw::LIBSSH2_ERROR_SFTP_PROTOCOL {
+            let status = unsafe { raw::libssh2_sftp_last_error(self.sftp) };
+            Err(Error::from_errno(status as c_int))
+        } else {
+            self.sess.rc(rc)
+        }
+    }
+
+    /// Like `LockedSftp::session_rc`, for calls made on a handle.
+    fn session_rc(&self, rc: c_int) -> Result<(), Error> {
+        self.notify(rc);
+        self.sess.rc(rc)
+    }
+
+    fn notify(&self, rc: c_int) {
+        notify_would_block(&self.sess, self.would_block, rc)
+    }
 }
 
 /// Metadata information about a remote file.
 ///
 /// Fields are not necessarily all provided
+///
+/// libssh2 only implements version 3 of the SFTP protocol, so the creation
+/// time and the dedicated file type byte introduced by later versions are
+/// never sent by the server. The file type is instead derived from the
+/// permission bits, see `file_type`.
+///
+/// Extended attributes cannot be read nor set either: `LIBSSH2_SFTP_ATTRIBUTES`
+/// has no field for the `extended` name/value pairs, and libssh2 skips them
+/// when decoding a reply. Tools preserving xattrs (SELinux contexts, ACLs)
+/// have to transfer them by other means, e.g. by running `getfattr` and
+/// `setfattr` over an exec channel.
+///
+/// The `ACL` attribute of version 4 and later isn't available for the same
+/// reason: libssh2 always negotiates version 3, in which servers don't send
+/// it, so there is nothing to capture nor to set back. `getfacl` and
+/// `setfacl` can likewise be run over an exec channel instead.
 #[derive(Debug, Clone, Eq, PartialEq)]
 #[allow(missing_copy_implementations)]
 pub struct FileStat {
@@ -80,6 +255,25 @@ pub struct FileStat {
     pub mtime: Option<u64>,
 }
 
+/// Metadata about a remote file, mirroring `std::fs::Metadata`.
+///
+/// This eases porting code written against `std::fs`; the underlying
+/// `FileStat` stays available through `stat`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Metadata {
+    stat: FileStat,
+}
+
+/// Metadata about a remote filesystem, as returned by `Sftp::statvfs`.
+///
+/// Servers provide it through the `statvfs@openssh.com` extension, which is
+/// a copy of the fields of statvfs(3). The raw fields stay available
+/// through `raw`.
+#[derive(Clone, Copy)]
+pub struct FsStat {
+    raw: raw::LIBSSH2_SFTP_STATVFS,
+}
+
 /// An structure representing a type of file.
 pub struct FileType {
     perm: c_ulong,
@@ -87,6 +281,11 @@ pub struct FileType {
 
 bitflags! {
     /// Options that can be used to configure how a file is opened
+    ///
+    /// There is no text mode: the flag asking the server to translate line
+    /// endings only appeared in version 4 of the SFTP protocol, and libssh2
+    /// always negotiates version 3. Files are transferred as is, so newlines
+    /// have to be converted by the caller when that is wanted.
     pub struct OpenFlags: c_ulong {
         /// Open the file for reading.
         const READ = raw::LIBSSH2_FXF_READ;
@@ -147,12 +346,25 @@ impl Sftp {
                 inner: Some(Arc::new(SftpInnerDropWrapper(Some(SftpInner {
                     raw,
                     sess: Arc::clone(sess),
+                    bytes_read: AtomicU64::new(0),
+                    bytes_written: AtomicU64::new(0),
+                    handles: Mutex::new(Vec::new()),
+                    would_block: Mutex::new(None),
+                    rate_limit: Mutex::new(None),
                 })))),
+                default_mode: DEFAULT_MODE,
+                cwd: None,
             })
         }
     }
 
     /// Open a handle to a file.
+    ///
+    /// libssh2 always sends `mode` along with the request, there is no way to
+    /// leave the permissions of a new file to the server. OpenSSH applies
+    /// its umask to the mode it is given, as `open(2)` does, so passing
+    /// `0o666` gets the same permissions as `ssh` and `scp` would, the
+    /// defaults of the server. Other servers may use the mode as is.
     pub fn open_mode(
         &self,
         filename: &Path,
@@ -160,6 +372,8 @@ impl Sftp {
         mode: i32,
         open_type: OpenType,
     ) -> Result<File, Error> {
+        trace_span!("sftp_open", path = %filename.display());
+        let path = filename;
         let filename = util::path2bytes(filename)?;
 
         let locked = self.lock()?;
@@ -173,9 +387,31 @@ impl Sftp {
                 open_type as c_int,
             );
             if ret.is_null() {
-                Err(locked.sess.last_error().unwrap_or_else(Error::unknown))
+                let err = locked.last_error();
+                // Servers speaking version 3 of the protocol have no status
+                // for an existing file and send a generic failure instead:
+                // check whether that is why an exclusive create failed.
+                if flags.contains(OpenFlags::EXCLUSIVE)
+                    && err.code() == raw::LIBSSH2_FX_FAILURE
+                    && raw::libssh2_sftp_stat_ex(
+                        locked.raw,
+                        filename.as_ptr() as *const _,
+                        filename.len() as c_uint,
+                        raw::LIBSSH2_SFTP_LSTAT,
+                        &mut mem::zeroed(),
+                    ) == 0
+                {
+                    return Err(Error::from_errno(raw::LIBSSH2_FX_FILE_ALREADY_EXISTS));
+                }
+                Err(err)
             } else {
-                Ok(File::from_raw(self, ret))
+                Ok(File::from_raw(
+                    self,
+                    ret,
+                    path.to_path_buf(),
+                    flags,
+                    open_type,
+                ))
             }
         }
     }
@@ -186,15 +422,181 @@ impl Sftp {
     }
 
     /// Helper to create a file in write-only mode with truncation.
+    ///
+    /// A file which doesn't exist yet is created with the mode set by
+    /// `set_default_mode`, 0o644 unless changed, before the server applies
+    /// its umask.
     pub fn create(&self, filename: &Path) -> Result<File, Error> {
         self.open_mode(
             filename,
             OpenFlags::WRITE | OpenFlags::TRUNCATE,
-            0o644,
+            self.default_mode,
             OpenType::File,
         )
     }
 
+    /// Sets the mode `create` and `create_write` give the files they create,
+    /// in place of 0o644.
+    ///
+    /// This only applies to this handle and to the clones made from it
+    /// afterwards, not to those which already exist. As with `open_mode`,
+    /// the server usually masks the mode with its umask, so that it can
+    /// take permissions away but not add them; `create_with_mode` sets
+    /// them exactly.
+    pub fn set_default_mode(&mut self, mode: i32) {
+        self.default_mode = mode;
+    }
+
+    /// Like `open`, but failing if the file that got opened doesn't have the
+    /// size and modification time in `expected`, as when it was replaced
+    /// since `expected` was obtained with `stat`.
+    ///
+    /// The check is made on the open handle, so the file can't be swapped
+    /// between it and the reads that follow; only the window between the
+    /// `stat` and the open remains. SFTP has no inode numbers to compare,
+    /// though, so a file replaced by one of the same size and modification
+    /// time goes unnoticed. Fields missing from `expected` are not checked.
+    /// A mismatch is reported with `LIBSSH2_ERROR_FILE`.
+    pub fn open_and_verify(&self, filename: &Path, expected: &FileStat) -> Result<File, Error> {
+        let mut file = self.open(filename)?;
+        let stat = file.stat()?;
+        let differs = |what, expected: Option<u64>, actual: Option<u64>| match expected {
+            Some(expected) if actual != Some(expected) => Some(Error::with_message(
+                raw::LIBSSH2_ERROR_FILE,
+                format!("{}: {} changed since stat", filename.display(), what),
+            )),
+            _ => None,
+        };
+        let mismatch = differs("size", expected.size, stat.size)
+            .or_else(|| differs("modification time", expected.mtime, stat.mtime));
+        match mismatch {
+            Some(err) => Err(err),
+            None => Ok(file),
+        }
+    }
+
+    /// Reads up to `len` bytes from `offset` in the file at `filename`,
+    /// opening it for just this read.
+    ///
+    /// The handle is positioned before anything is read, so no data is
+    /// read ahead from the start of the file and dropped. Fewer bytes than
+    /// `len` are returned only when the end of the file comes first, and
+    /// none at all when `offset` is at or past it. The buffer grows as data
+    /// comes in, so a `len` larger than the file, as for an open-ended
+    /// range, doesn't allocate more than the file holds.
+    pub fn read_range(&self, filename: &Path, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let file = self.open(filename)?;
+        file.seek_raw(offset)?;
+        let mut data = Vec::new();
+        let mut buf = vec![0; cmp::min(len, TRANSFER_BUF_SIZE)];
+        while data.len() < len {
+            let want = cmp::min(len - data.len(), buf.len());
+            match file.read_raw(&mut buf[..want])? {
+                0 => break,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Sets the directory the `_rel` methods, such as `open_rel`, resolve
+    /// relative paths against, as a working directory would.
+    ///
+    /// SFTP has no working directory: the server resolves relative paths
+    /// against the directory it started in, usually the user's home, and
+    /// the other methods keep doing so. A relative `dir` is itself taken
+    /// relative to the current one. The directory isn't checked, so setting
+    /// one which doesn't exist only makes the calls that use it fail. This
+    /// applies to this handle and the clones made from it afterwards.
+    pub fn set_cwd(&mut self, dir: &Path) {
+        self.cwd = Some(self.cwd_join(dir));
+    }
+
+    /// The directory set with `set_cwd`, if any.
+    pub fn cwd(&self) -> Option<&Path> {
+        match self.cwd {
+            Some(ref cwd) => Some(cwd.as_path()),
+            None => None,
+        }
+    }
+
+    /// Resolves `path` against the directory set with `set_cwd`, as the
+    /// `_rel` methods do, so that it can be passed to the other methods.
+    ///
+    /// Absolute paths, and any path when no directory was set, are returned
+    /// as they are.
+    pub fn cwd_join(&self, path: &Path) -> PathBuf {
+        match self.cwd {
+            Some(ref cwd) => cwd.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Like `open`, with `filename` resolved by `cwd_join`.
+    pub fn open_rel(&self, filename: &Path) -> Result<File, Error> {
+        self.open(&self.cwd_join(filename))
+    }
+
+    /// Like `stat`, with `filename` resolved by `cwd_join`.
+    pub fn stat_rel(&self, filename: &Path) -> Result<FileStat, Error> {
+        self.stat(&self.cwd_join(filename))
+    }
+
+    /// Like `readdir`, with `dirname` resolved by `cwd_join`.
+    ///
+    /// The entries are joined to the resolved directory, not to `dirname`.
+    pub fn readdir_rel(&self, dirname: &Path) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+        self.readdir(&self.cwd_join(dirname))
+    }
+
+    /// Like `open`, but returning a handle which can only be read from.
+    pub fn open_read(&self, filename: &Path) -> Result<ReadFile, Error> {
+        Ok(ReadFile(self.open(filename)?))
+    }
+
+    /// Like `create`, but returning a handle which can only be written to.
+    pub fn create_write(&self, filename: &Path) -> Result<WriteFile, Error> {
+        Ok(WriteFile(self.create(filename)?))
+    }
+
+    /// Helper to create a file in write-only mode with truncation, ending up
+    /// with the permissions given by `mode`.
+    ///
+    /// The mode passed to `open_mode` is only used by the server when the
+    /// file doesn't exist yet, and is then typically masked by the umask of
+    /// the server process. To make the result deterministic, the permissions
+    /// are explicitly set on the handle once it has been opened, which also
+    /// applies them when an existing file was truncated.
+    pub fn create_with_mode(&self, filename: &Path, mode: i32) -> Result<File, Error> {
+        let mut file = self.open_mode(
+            filename,
+            OpenFlags::WRITE | OpenFlags::TRUNCATE,
+            mode,
+            OpenType::File,
+        )?;
+        file.setstat(FileStat::empty().with_perm(mode as u32))?;
+        Ok(file)
+    }
+
+    /// Open a handle to a file named relative to a directory handle.
+    ///
+    /// SFTP has no `openat`: this joins `name` to the path `dir` was opened
+    /// with and opens the result, so it is resolved again by the server.
+    /// An absolute `name` is opened as is.
+    pub fn open_in(
+        &self,
+        dir: &File,
+        name: &Path,
+        flags: OpenFlags,
+        mode: i32,
+        open_type: OpenType,
+    ) -> Result<File, Error> {
+        match dir.inner.as_ref() {
+            Some(dir) => self.open_mode(&dir.path.join(name), flags, mode, open_type),
+            None => Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+        }
+    }
+
     /// Helper to open a directory for reading its contents.
     pub fn opendir(&self, dirname: &Path) -> Result<File, Error> {
         self.open_mode(dirname, OpenFlags::READ, 0, OpenType::Dir)
@@ -205,29 +607,93 @@ impl Sftp {
     /// The returned paths are all joined with `dirname` when returned, and the
     /// paths `.` and `..` are filtered out of the returned list.
     pub fn readdir(&self, dirname: &Path) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+        self.read_dir_filtered(dirname, |_, _| true)
+    }
+
+    /// Like `readdir`, but only keeping the entries for which `predicate`
+    /// returns `true`.
+    ///
+    /// The predicate is called with the name of each entry, not joined with
+    /// `dirname`, as the entries are received, so that those which are not
+    /// wanted are never collected. The server still sends all of them.
+    pub fn read_dir_filtered<F>(
+        &self,
+        dirname: &Path,
+        predicate: F,
+    ) -> Result<Vec<(PathBuf, FileStat)>, Error>
+    where
+        F: Fn(&Path, &FileStat) -> bool,
+    {
         let mut dir = self.opendir(dirname)?;
         let mut ret = Vec::new();
-        loop {
-            match dir.readdir() {
-                Ok((filename, stat)) => {
-                    if &*filename == Path::new(".") || &*filename == Path::new("..") {
+        while let Some((filename, stat)) = dir.next_entry()? {
+            if &*filename == Path::new(".") || &*filename == Path::new("..") {
+                continue;
+            }
+            if !predicate(&filename, &stat) {
+                continue;
+            }
+
+            ret.push((dirname.join(&filename), stat))
+        }
+        Ok(ret)
+    }
+
+    /// The total size, in bytes, of the regular files in the tree rooted at
+    /// the directory `path`, like `du -b`.
+    ///
+    /// Symbolic links are not followed, and count for nothing, nor do the
+    /// directories themselves. Files listed without a size count as empty.
+    /// The walk stops at the first directory which can't be listed; use
+    /// `dir_size_skipping` to carry on past them.
+    pub fn dir_size(&self, path: &Path) -> Result<u64, Error> {
+        self.dir_size_ex(path, None)
+    }
+
+    /// Like `dir_size`, but skipping the subdirectories which can't be
+    /// listed, such as those the user has no permission to read. They are
+    /// returned along with the error each gave, so that the total can be
+    /// reported as a lower bound.
+    pub fn dir_size_skipping(&self, path: &Path) -> Result<(u64, Vec<(PathBuf, Error)>), Error> {
+        let mut skipped = Vec::new();
+        let total = self.dir_size_ex(path, Some(&mut skipped))?;
+        Ok((total, skipped))
+    }
+
+    fn dir_size_ex(
+        &self,
+        path: &Path,
+        mut skipped: Option<&mut Vec<(PathBuf, Error)>>,
+    ) -> Result<u64, Error> {
+        let mut total = 0;
+        let mut dirs = vec![path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match self.readdir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => match skipped {
+                    Some(ref mut skipped) if dir != path => {
+                        skipped.push((dir, err));
                         continue;
                     }
-
-                    ret.push((dirname.join(&filename), stat))
+                    _ => return Err(err),
+                },
+            };
+            for (entry, stat) in entries {
+                if stat.is_dir() {
+                    dirs.push(entry);
+                } else if stat.is_file() {
+                    total += stat.size.unwrap_or(0);
                 }
-                Err(ref e) if e.code() == raw::LIBSSH2_ERROR_FILE => break,
-                Err(e) => return Err(e),
             }
         }
-        Ok(ret)
+        Ok(total)
     }
 
     /// Create a directory on the remote file system.
     pub fn mkdir(&self, filename: &Path, mode: i32) -> Result<(), Error> {
         let filename = util::path2bytes(filename)?;
         let locked = self.lock()?;
-        locked.sess.rc(unsafe {
+        locked.session_rc(unsafe {
             raw::libssh2_sftp_mkdir_ex(
                 locked.raw,
                 filename.as_ptr() as *const _,
@@ -241,7 +707,7 @@ impl Sftp {
     pub fn rmdir(&self, filename: &Path) -> Result<(), Error> {
         let filename = util::path2bytes(filename)?;
         let locked = self.lock()?;
-        locked.sess.rc(unsafe {
+        locked.session_rc(unsafe {
             raw::libssh2_sftp_rmdir_ex(
                 locked.raw,
                 filename.as_ptr() as *const _,
@@ -252,6 +718,7 @@ impl Sftp {
 
     /// Get the metadata for a file, performed by stat(2)
     pub fn stat(&self, filename: &Path) -> Result<FileStat, Error> {
+        trace_span!("sftp_stat", path = %filename.display());
         let filename = util::path2bytes(filename)?;
         let locked = self.lock()?;
         unsafe {
@@ -263,7 +730,7 @@ impl Sftp {
                 raw::LIBSSH2_SFTP_STAT,
                 &mut ret,
             );
-            locked.sess.rc(rc)?;
+            locked.session_rc(rc)?;
             Ok(FileStat::from_raw(&ret))
         }
     }
@@ -281,16 +748,70 @@ impl Sftp {
                 raw::LIBSSH2_SFTP_LSTAT,
                 &mut ret,
             );
-            locked.sess.rc(rc)?;
+            locked.session_rc(rc)?;
             Ok(FileStat::from_raw(&ret))
         }
     }
 
+    /// Get the metadata for a file, following symlinks like `stat` if
+    /// `follow_symlinks` is set and describing the link itself like `lstat`
+    /// otherwise.
+    pub fn metadata_opt(&self, filename: &Path, follow_symlinks: bool) -> Result<FileStat, Error> {
+        if follow_symlinks {
+            self.stat(filename)
+        } else {
+            self.lstat(filename)
+        }
+    }
+
+    /// Get the metadata of the filesystem holding `path`.
+    ///
+    /// This requires the `statvfs@openssh.com` extension: other servers
+    /// answer with `LIBSSH2_FX_OP_UNSUPPORTED`.
+    pub fn statvfs(&self, path: &Path) -> Result<FsStat, Error> {
+        let path = util::path2bytes(path)?;
+        let locked = self.lock()?;
+        unsafe {
+            let mut ret = mem::zeroed();
+            locked.rc(raw::libssh2_sftp_statvfs(
+                locked.raw,
+                path.as_ptr() as *const _,
+                path.len() as size_t,
+                &mut ret,
+            ))?;
+            Ok(FsStat::from(ret))
+        }
+    }
+
+    /// Whether the filesystem holding `path` has at least `needed` inodes
+    /// left, so that creating as many files won't fail for lack of them even
+    /// with bytes to spare.
+    ///
+    /// Filesystems which allocate inodes as needed report no inodes at all,
+    /// and are taken to always have enough. See `statvfs` for the servers
+    /// which support this.
+    pub fn has_free_inodes(&self, path: &Path, needed: u64) -> Result<bool, Error> {
+        let stat = self.statvfs(path)?;
+        Ok(stat.total_inodes() == 0 || stat.free_inodes() >= needed)
+    }
+
+    /// Get the metadata for a file in the shape of `std::fs::metadata`,
+    /// following symlinks.
+    pub fn metadata(&self, filename: &Path) -> Result<Metadata, Error> {
+        self.stat(filename).map(Metadata::from)
+    }
+
+    /// Get the metadata for a file in the shape of
+    /// `std::fs::symlink_metadata`, without following symlinks.
+    pub fn symlink_metadata(&self, filename: &Path) -> Result<Metadata, Error> {
+        self.lstat(filename).map(Metadata::from)
+    }
+
     /// Set the metadata for a file.
     pub fn setstat(&self, filename: &Path, stat: FileStat) -> Result<(), Error> {
         let filename = util::path2bytes(filename)?;
         let locked = self.lock()?;
-        locked.sess.rc(unsafe {
+        locked.session_rc(unsafe {
             let mut raw = stat.raw();
             raw::libssh2_sftp_stat_ex(
                 locked.raw,
@@ -307,7 +828,7 @@ impl Sftp {
         let path = util::path2bytes(path)?;
         let target = util::path2bytes(target)?;
         let locked = self.lock()?;
-        locked.sess.rc(unsafe {
+        locked.session_rc(unsafe {
             raw::libssh2_sftp_symlink_ex(
                 locked.raw,
                 path.as_ptr() as *const _,
@@ -329,8 +850,26 @@ impl Sftp {
         self.readlink_op(path, raw::LIBSSH2_SFTP_REALPATH)
     }
 
-    fn readlink_op(&self, path: &Path, op: c_int) -> Result<PathBuf, Error> {
-        let path = util::path2bytes(path)?;
+    /// Get the metadata for `path` following symlinks, like `stat`, along
+    /// with the target of the link if `path` is one.
+    ///
+    /// This sends a readlink request, then a stat request for `path`: two
+    /// round trips, where classifying the path with `lstat` first would take
+    /// three for a link. The target is returned as stored in the link, which
+    /// may be relative to its directory. A dangling link fails as `stat`
+    /// does: use `readlink` to get its target.
+    pub fn stat_with_target(&self, path: &Path) -> Result<(Option<PathBuf>, FileStat), Error> {
+        let target = match self.readlink(path) {
+            Ok(target) => Some(target),
+            // Not a link, or no such path, which `stat` reports below
+            Err(ref e) if e.code() == raw::LIBSSH2_ERROR_SFTP_PROTOCOL => None,
+            Err(e) => return Err(e),
+        };
+        Ok((target, self.stat(path)?))
+    }
+
+    fn readlink_op(&self, filename: &Path, op: c_int) -> Result<PathBuf, Error> {
+        let path = util::path2bytes(filename)?;
         let mut ret = Vec::<u8>::with_capacity(128);
         let mut rc;
         let locked = self.lock()?;
@@ -346,13 +885,13 @@ impl Sftp {
                 )
             };
             if rc == raw::LIBSSH2_ERROR_BUFFER_TOO_SMALL {
-                let cap = ret.capacity();
-                ret.reserve(cap);
+                grow_name_buf(&mut ret, filename)?;
             } else {
                 break;
             }
         }
         if rc < 0 {
+            locked.notify(rc);
             Err(Error::from_session_error_raw(locked.sess.raw, rc))
         } else {
             unsafe { ret.set_len(rc as usize) }
@@ -372,13 +911,19 @@ impl Sftp {
     /// operation and/or using native system calls when possible.
     ///
     /// If no flags are specified then all flags are used.
+    ///
+    /// Servers that do not support the extended rename answer with an
+    /// "operation unsupported" status when flags are sent. In that case the
+    /// rename is attempted once more without flags, as the basic rename of
+    /// version 3 of the protocol, whose behavior when the destination exists
+    /// is up to the server.
     pub fn rename(&self, src: &Path, dst: &Path, flags: Option<RenameFlags>) -> Result<(), Error> {
         let flags =
             flags.unwrap_or(RenameFlags::ATOMIC | RenameFlags::OVERWRITE | RenameFlags::NATIVE);
         let src = util::path2bytes(src)?;
         let dst = util::path2bytes(dst)?;
         let locked = self.lock()?;
-        locked.sess.rc(unsafe {
+        let rename = |flags: RenameFlags| unsafe {
             raw::libssh2_sftp_rename_ex(
                 locked.raw,
                 src.as_ptr() as *const _,
@@ -387,117 +932,1002 @@ impl Sftp {
                 dst.len() as c_uint,
                 flags.bits(),
             )
-        })
+        };
+        let mut rc = rename(flags);
+        if !flags.is_empty() && locked.sftp_status(rc) == Some(raw::LIBSSH2_FX_OP_UNSUPPORTED) {
+            rc = rename(RenameFlags::empty());
+        }
+        locked.rc(rc)
+    }
+
+    /// Replace the contents of the file at `path` with `data`, so that a
+    /// reader sees either the previous contents or the new ones, never a
+    /// partial write.
+    ///
+    /// The data is written to a temporary file next to `path`, synced to
+    /// disk when the server supports `fsync@openssh.com`, and renamed over
+    /// `path`. The file keeps the permissions of the one it replaces, or
+    /// gets the mode set by `set_default_mode`; its owner is that of the
+    /// session's user. The temporary file is removed again on failure.
+    ///
+    /// libssh2 can't send the `posix-rename@openssh.com` extension, and
+    /// servers speaking version 3 of the protocol, OpenSSH included, refuse
+    /// to rename over an existing file. In that case `path` is removed just
+    /// before the temporary file is renamed: readers opening it in between
+    /// find no file, though still never a partial one.
+    pub fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => {
+                return Err(Error::new(
+                    raw::LIBSSH2_ERROR_INVAL,
+                    "no file name to write to",
+                ))
+            }
+        };
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}.tmp", nonce));
+        let tmp = path.with_file_name(tmp_name);
+
+        let existing = self.stat(path).ok();
+        let perm = existing.as_ref().and_then(|stat| stat.perm);
+        let mut file = self.open_mode(
+            &tmp,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE,
+            perm.map_or(self.default_mode, |perm| perm as i32),
+            OpenType::File,
+        )?;
+        let write = |file: &mut File| -> Result<(), Error> {
+            if let Some(perm) = perm {
+                file.setstat(FileStat::empty().with_perm(perm & 0o7777))?;
+            }
+            file.write_all_raw(data)?;
+            match file.sync_all() {
+                Err(ref e) if e.code() == raw::LIBSSH2_FX_OP_UNSUPPORTED => Ok(()),
+                res => res,
+            }
+        };
+        let res = write(&mut file)
+            .and_then(|()| file.close_checked())
+            .and_then(|()| match self.rename(&tmp, path, None) {
+                Err(ref e) if e.code() == raw::LIBSSH2_FX_FAILURE && existing.is_some() => {
+                    self.unlink(path)?;
+                    self.rename(&tmp, path, None)
+                }
+                res => res,
+            });
+        if res.is_err() {
+            let _ = self.unlink(&tmp);
+        }
+        res
+    }
+
+    /// Move `src` to `dst`, even across filesystems of the server, like
+    /// `mv`.
+    ///
+    /// A `rename` is tried first, with its default flags, so `dst` is
+    /// replaced if it exists. Servers speaking version 3 of the protocol
+    /// report both a `dst` which exists and a move across filesystems as a
+    /// generic failure. An existing `dst` which isn't a directory is then
+    /// removed and the rename tried again, leaving a moment when neither
+    /// the old nor the new file is there. If it still fails, a regular file
+    /// is copied instead, through this client as libssh2 doesn't expose the
+    /// `copy-data` extension, then `src` is removed. The copy keeps the
+    /// permissions and timestamps of `src`, but not its owner, and isn't
+    /// atomic: `dst` is only complete once this returns, and is removed
+    /// again if the copy fails. Directories and other files are never
+    /// copied, the rename error is returned for them.
+    pub fn move_file(&self, src: &Path, dst: &Path) -> Result<(), Error> {
+        let err = match self.rename(src, dst, None) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if err.code() != raw::LIBSSH2_FX_FAILURE {
+            return Err(err);
+        }
+        let stat = self.lstat(src)?;
+        let err = match self.lstat(dst) {
+            Ok(ref existing) if !existing.is_dir() => {
+                self.unlink(dst)?;
+                match self.rename(src, dst, None) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => err,
+                }
+            }
+            _ => err,
+        };
+        if err.code() != raw::LIBSSH2_FX_FAILURE || !stat.is_file() {
+            return Err(err);
+        }
+        let mut from = self.open(src)?;
+        let mut to = self.open_mode(
+            dst,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            stat.perm.unwrap_or(0o644) as i32,
+            OpenType::File,
+        )?;
+        let copy = |from: &mut File, to: &mut File| -> Result<(), Error> {
+            let mut buf = vec![0; TRANSFER_BUF_SIZE];
+            loop {
+                match from.read_raw(&mut buf)? {
+                    0 => break,
+                    n => to.write_all_raw(&buf[..n])?,
+                }
+            }
+            let mut attrs = FileStat::empty();
+            attrs.perm = stat.perm;
+            if let (Some(atime), Some(mtime)) = (stat.atime, stat.mtime) {
+                attrs = attrs.with_atime(atime).with_mtime(mtime);
+            }
+            to.setstat(attrs)
+        };
+        let res = copy(&mut from, &mut to).and_then(|()| to.close_checked());
+        drop(from);
+        if let Err(err) = res {
+            let _ = self.unlink(dst);
+            return Err(err);
+        }
+        self.unlink(src)
+    }
+
+    /// Create the file at `path` if it doesn't exist, empty, and set its
+    /// access and modification times to now, like `touch`.
+    ///
+    /// Version 3 of the protocol has no way to ask for the current time of
+    /// the server, so the times are taken from the clock of this machine:
+    /// they are off by as much as the two clocks differ. A new file gets
+    /// the default permissions of the server, as with a mode of `0o666`.
+    pub fn touch(&self, path: &Path) -> Result<(), Error> {
+        let mut file = self.open_mode(
+            path,
+            OpenFlags::WRITE | OpenFlags::CREATE,
+            0o666,
+            OpenType::File,
+        )?;
+        let now = unix_secs(SystemTime::now()).unwrap_or(0);
+        file.setstat(FileStat::empty().with_atime(now).with_mtime(now))
     }
 
     /// Remove a file on the remote filesystem
     pub fn unlink(&self, file: &Path) -> Result<(), Error> {
         let file = util::path2bytes(file)?;
         let locked = self.lock()?;
-        locked.sess.rc(unsafe {
+        locked.session_rc(unsafe {
             raw::libssh2_sftp_unlink_ex(locked.raw, file.as_ptr() as *const _, file.len() as c_uint)
         })
     }
 
-    fn lock(&self) -> Result<LockedSftp, Error> {
-        match self.inner.as_ref() {
-            Some(sftp_inner_drop_wrapper) => {
-                let sftp_inner = sftp_inner_drop_wrapper
-                    .0
-                    .as_ref()
-                    .expect("Never unset until shutdown, in which case inner is also unset");
-                let sess = sftp_inner.sess.lock();
-                Ok(LockedSftp {
-                    sess,
-                    raw: sftp_inner.raw,
-                })
+    /// Checks whether the server supports the fsync@openssh.com extension,
+    /// which `File::sync_all` relies on, so that durability problems can be
+    /// reported before any data is written.
+    ///
+    /// libssh2 doesn't keep the list of extensions the server advertised, so
+    /// this asks the server to sync a handle to the home directory: servers
+    /// without the extension reply `LIBSSH2_FX_OP_UNSUPPORTED`, and others
+    /// either succeed or, as OpenSSH does for a directory, refuse the handle
+    /// with `LIBSSH2_FX_FAILURE`, `LIBSSH2_FX_NO_SUCH_FILE` or
+    /// `LIBSSH2_FX_INVALID_HANDLE`. Any other error, such as a lost
+    /// connection, is returned. This takes a few round trips, and the answer
+    /// is worth keeping.
+    pub fn supports_fsync(&self) -> Result<bool, Error> {
+        let mut dir = self.opendir(Path::new("."))?;
+        match dir.sync_all() {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.code() == raw::LIBSSH2_FX_OP_UNSUPPORTED => Ok(false),
+            Err(ref e)
+                if e.code() == raw::LIBSSH2_FX_FAILURE
+                    || e.code() == raw::LIBSSH2_FX_NO_SUCH_FILE
+                    || e.code() == raw::LIBSSH2_FX_INVALID_HANDLE =>
+            {
+                Ok(true)
             }
-            None => Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+            Err(e) => Err(e),
         }
     }
 
-    // This method is used by the async ssh crate
-    #[doc(hidden)]
-    pub fn shutdown(&mut self) -> Result<(), Error> {
-        // We cannot shutdown the SFTP if files are still open, etc, as these store a ref to the sftp in libssh2.
-        // We have to make sure we are the last reference to it.
-        match self.inner.take() {
-            Some(sftp_inner_arc) => {
-                // We were not already un-initialized
-                match Arc::try_unwrap(sftp_inner_arc) {
-                    Ok(mut sftp_inner_wrapper) => {
-                        // Early drop
-                        let sftp_inner = sftp_inner_wrapper.0.take().expect(
-                            "We were holding an Arc<SftpInnerDropWrapper>, \
-                                    so nobody could unset this (set on creation)",
-                        );
-                        sftp_inner
-                            .sess
-                            .lock()
-                            .rc(unsafe { raw::libssh2_sftp_shutdown(sftp_inner.raw) })?;
-                        Ok(())
-                    }
-                    Err(sftp_inner_arc) => {
-                        // We are failing shutdown as there are files left open, keep this object usable
-                        self.inner = Some(sftp_inner_arc);
-                        Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE))
-                    }
-                }
+    /// The software the server identified itself as, such as
+    /// `OpenSSH_9.6p1`.
+    ///
+    /// This is the "softwareversion" part of the banner the server sent when
+    /// the session was established, as per RFC 4253, so it names the SSH
+    /// server rather than the SFTP subsystem, which usually ships with it.
+    /// It isn't authenticated and can be configured to anything: use it as
+    /// a hint, for logging or to skip known-broken extensions, and fall back
+    /// gracefully when an extension turns out to be missing anyway.
+    pub fn server_software(&self) -> Option<String> {
+        let locked = self.lock().ok()?;
+        let banner = unsafe {
+            let banner = raw::libssh2_session_banner_get(locked.sess.raw);
+            if banner.is_null() {
+                return None;
             }
-            None => {
-                // We have already shut this down. Shutting down twice is a mistake from the caller code
-                Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE))
+            CStr::from_ptr(banner).to_string_lossy()
+        };
+        let version = banner.trim().splitn(3, '-').nth(2)?;
+        version.split_whitespace().next().map(str::to_string)
+    }
+
+    /// Remove several files on the remote filesystem, returning the outcome
+    /// for each path in order.
+    ///
+    /// The session is locked once for the whole batch rather than once per
+    /// file. libssh2 only keeps track of a single unlink request at a time
+    /// on an SFTP channel, so the removals still each wait for the server's
+    /// reply; spread the paths over an `SftpPool` to have several in flight.
+    pub fn unlink_many(&self, paths: &[PathBuf]) -> Vec<Result<(), Error>> {
+        let locked = match self.lock() {
+            Ok(locked) => locked,
+            Err(_) => {
+                return paths
+                    .iter()
+                    .map(|_| Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)))
+                    .collect()
             }
+        };
+        paths
+            .iter()
+            .map(|path| {
+                let file = util::path2bytes(path)?;
+                locked.session_rc(unsafe {
+                    raw::libssh2_sftp_unlink_ex(
+                        locked.raw,
+                        file.as_ptr() as *const _,
+                        file.len() as c_uint,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Wraps a clone of this channel in a cache of the results of `stat`
+    /// and `lstat`, holding at most `capacity` paths.
+    ///
+    /// See `StatCache` for when cached results go stale.
+    pub fn with_stat_cache(&self, capacity: usize) -> StatCache {
+        StatCache {
+            sftp: self.clone(),
+            capacity,
+            entries: Mutex::new(CacheEntries {
+                stats: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                generation: 0,
+            }),
         }
     }
-}
 
-impl Drop for SftpInnerDropWrapper {
-    fn drop(&mut self) {
-        // Check we were not early-dropped
-        if let Some(inner) = self.0.take() {
-            let sess = inner.sess.lock();
-            // Set ssh2 to blocking during the drop
-            let was_blocking = sess.is_blocking();
-            sess.set_blocking(true);
-            // The shutdown statement can go wrong and return an error code, but we are too late
-            // in the execution to recover it.
-            let _shutdown_result = unsafe { raw::libssh2_sftp_shutdown(inner.raw) };
-            sess.set_blocking(was_blocking);
+    /// Whether another thread is currently running an operation on the
+    /// session this channel belongs to.
+    fn is_busy(&self) -> bool {
+        match self.inner() {
+            Some(sftp_inner) => sftp_inner.sess.try_lock().is_none(),
+            None => false,
         }
     }
-}
 
-impl File {
-    /// Wraps a raw pointer in a new File structure tied to the lifetime of the
-    /// given session.
+    /// The total number of bytes read from files opened through this
+    /// channel, by any of its clones.
     ///
-    /// This consumes ownership of `raw`.
-    unsafe fn from_raw(sftp: &Sftp, raw: *mut raw::LIBSSH2_SFTP_HANDLE) -> File {
-        File {
-            inner: Some(FileInner {
-                raw,
-                sftp: Arc::clone(
-                    &sftp
-                        .inner
-                        .as_ref()
-                        .expect("Cannot open file after sftp shutdown"),
-                ),
-            }),
+    /// Only the file data successfully received is counted: neither the
+    /// protocol overhead nor requests which failed are. Data fetched by
+    /// `File::set_read_ahead` counts even if it ends up unused. The count
+    /// is 0 once the channel is shut down.
+    pub fn bytes_read(&self) -> u64 {
+        match self.inner() {
+            Some(sftp_inner) => sftp_inner.bytes_read.load(Ordering::Relaxed),
+            None => 0,
         }
     }
 
-    /// Set the metadata for this handle.
-    pub fn setstat(&mut self, stat: FileStat) -> Result<(), Error> {
-        let locked = self.lock()?;
-        locked.sess.rc(unsafe {
-            let mut raw = stat.raw();
-            raw::libssh2_sftp_fstat_ex(locked.raw, &mut raw, 1)
-        })
+    /// The total number of bytes written to files opened through this
+    /// channel, by any of its clones.
+    ///
+    /// Like `bytes_read`, only the data the server acknowledged is counted.
+    pub fn bytes_written(&self) -> u64 {
+        match self.inner() {
+            Some(sftp_inner) => sftp_inner.bytes_written.load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    /// Sets a callback run whenever an operation on this channel, or on a
+    /// file opened through it, is about to report `LIBSSH2_ERROR_EAGAIN`,
+    /// with the directions the session is blocked in. It replaces any
+    /// previous callback, for all the clones of this `Sftp`.
+    ///
+    /// This only happens in non-blocking mode, right before the error is
+    /// returned and turned into `ErrorKind::WouldBlock` by `Read` and
+    /// `Write`: in blocking mode libssh2 waits on the socket internally,
+    /// without returning until the operation went through, so there are no
+    /// retries to observe.
+    ///
+    /// The session is locked while the callback runs, which must therefore
+    /// not use it, nor any object opened from it.
+    pub fn on_would_block<F>(&self, cb: F)
+    where
+        F: Fn(BlockDirections) + Send + Sync + 'static,
+    {
+        if let Some(sftp_inner) = self.inner() {
+            *sftp_inner.would_block.lock() = Some(Box::new(cb));
+        }
+    }
+
+    /// Caps the throughput of the transfer helpers of this channel and its
+    /// clones to `max_bytes_per_sec`, or lifts the cap with `None`.
+    ///
+    /// This applies to `upload_path`, `resume_upload`, `upload_sparse`,
+    /// `download_path` and `download_path_verified`, as well as `Mirror`,
+    /// each transfer being paced on its own: running several at once adds
+    /// their rates up. The data is sent in chunks of at most an eighth of
+    /// a second's worth, and after each chunk the transfer sleeps as long
+    /// as needed for the average rate, time spent on the chunk included,
+    /// to stay under the cap. Only file data is counted, not the protocol
+    /// overhead. Transfers already running keep the cap they started with.
+    pub fn set_transfer_rate(&self, max_bytes_per_sec: Option<u64>) {
+        if let Some(sftp_inner) = self.inner() {
+            *sftp_inner.rate_limit.lock() = max_bytes_per_sec;
+        }
+    }
+
+    /// The number of files opened through this channel, by any of its
+    /// clones, which are still open.
+    ///
+    /// Files are only tracked, not kept alive, so this is how many `File`
+    /// objects exist that were neither dropped nor closed: a count which
+    /// keeps growing points to a leak.
+    pub fn open_handle_count(&self) -> usize {
+        match self.inner() {
+            Some(sftp_inner) => {
+                let _sess = sftp_inner.sess.lock();
+                let handles = sftp_inner.handles.lock();
+                handles
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .filter(|raw| !raw.load(Ordering::Relaxed).is_null())
+                    .count()
+            }
+            None => 0,
+        }
+    }
+
+    /// Closes all the files still open on this channel.
+    ///
+    /// The `File` objects stay around, but behave as if they had been
+    /// closed: their operations fail with `LIBSSH2_ERROR_BAD_USE`. As when
+    /// they are dropped, each handle is closed in blocking mode, waiting at
+    /// most 10 seconds for the server. The first failure is returned, once
+    /// all handles are closed.
+    ///
+    /// The channel still can't be shut down with `shutdown` until these
+    /// objects are dropped, but nothing is left open on the server.
+    pub fn close_all(&self) -> Result<(), Error> {
+        let sftp_inner = match self.inner() {
+            Some(sftp_inner) => sftp_inner,
+            None => return Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+        };
+        let locked = self.lock()?;
+        // `mem::take` is too recent for the versions of Rust supported here
+        #[allow(unknown_lints, clippy::mem_replace_with_default)]
+        let handles = mem::replace(&mut *sftp_inner.handles.lock(), Vec::new());
+        let mut res = Ok(());
+        for handle in handles.iter().filter_map(Weak::upgrade) {
+            let raw = handle.swap(ptr::null_mut(), Ordering::Relaxed);
+            if raw.is_null() {
+                continue;
+            }
+            let rc = blocking_for_drop(&locked.sess, || unsafe {
+                raw::libssh2_sftp_close_handle(raw)
+            });
+            if res.is_ok() {
+                res = locked.rc(rc);
+            }
+        }
+        res
+    }
+
+    fn inner(&self) -> Option<&SftpInner> {
+        self.inner.as_ref().and_then(|wrapper| wrapper.0.as_ref())
+    }
+
+    fn lock(&self) -> Result<LockedSftp, Error> {
+        match self.inner.as_ref() {
+            Some(sftp_inner_drop_wrapper) => {
+                let sftp_inner = sftp_inner_drop_wrapper
+                    .0
+                    .as_ref()
+                    .expect("Never unset until shutdown, in which case inner is also unset");
+                let sess = sftp_inner.sess.lock();
+                Ok(LockedSftp {
+                    sess,
+                    raw: sftp_inner.raw,
+                    would_block: &sftp_inner.would_block,
+                })
+            }
+            None => Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+        }
+    }
+
+    /// Upload the local file at `local` to `remote`, giving it the same
+    /// permissions and timestamps.
+    ///
+    /// An existing remote file is overwritten. If `local` is a symlink, it
+    /// is recreated as a remote symlink with the same target instead of
+    /// being followed; upload `fs::canonicalize(local)` to copy the file it
+    /// points to.
+    pub fn upload_path(&self, local: &Path, remote: &Path) -> Result<(), Error> {
+        let meta = fs::symlink_metadata(local).map_err(|e| util::file_error(local, e))?;
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(local).map_err(|e| util::file_error(local, e))?;
+            return self.symlink(&target, remote);
+        }
+
+        let mut src = fs::File::open(local).map_err(|e| util::file_error(local, e))?;
+        let perm = local_perm(&meta);
+        let mut dst = self.open_mode(
+            remote,
+            OpenFlags::WRITE | OpenFlags::TRUNCATE,
+            perm as i32,
+            OpenType::File,
+        )?;
+        let mut throttle = self.throttle();
+        let mut buf = vec![0; throttle.buf_size()];
+        loop {
+            match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    dst.write_all_raw(&buf[..n])?;
+                    throttle.pace(n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(util::file_error(local, e)),
+            }
+        }
+
+        // The server leaves the permissions to its umask and the times to
+        // those of the write, so set them once the data is in.
+        dst.setstat(uploaded_stat(&meta))
+    }
+
+    /// Resume an `upload_path` of the local file at `local` to `remote` which
+    /// was interrupted, returning how many bytes were already uploaded and
+    /// did not need to be sent again.
+    ///
+    /// The remote file is compared with the local one block by block, and the
+    /// upload restarts at the first block which differs, so that a prefix
+    /// which was corrupted or modified since is not kept. libssh2 gives no
+    /// access to the `check-file` checksum extension, so the remote blocks
+    /// are downloaded to be compared: this only pays off when uploading is
+    /// slower than downloading, which is the common case. Data beyond the
+    /// size of `local` is cut off, and permissions and timestamps are set as
+    /// by `upload_path`. Unlike it, `local` is always followed if it is a
+    /// symlink.
+    pub fn resume_upload(&self, local: &Path, remote: &Path) -> Result<u64, Error> {
+        let meta = fs::metadata(local).map_err(|e| util::file_error(local, e))?;
+        let mut src = fs::File::open(local).map_err(|e| util::file_error(local, e))?;
+        let mut dst = self.open_mode(
+            remote,
+            OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+            local_perm(&meta) as i32,
+            OpenType::File,
+        )?;
+
+        let mut throttle = self.throttle();
+        let mut buf = vec![0; throttle.buf_size()];
+        let mut remote_buf = vec![0; buf.len()];
+        let mut resumed = 0;
+        let mut n;
+        loop {
+            n = read_full(&mut src, &mut buf).map_err(|e| util::file_error(local, e))?;
+            if n == 0 {
+                break;
+            }
+            let received = dst.read_full_raw(&mut remote_buf[..n])?;
+            throttle.pace(received);
+            if received < n || buf[..n] != remote_buf[..n] {
+                break;
+            }
+            resumed += n as u64;
+        }
+
+        // `buf` holds the first block to send, if any
+        dst.seek_raw(resumed)?;
+        while n > 0 {
+            dst.write_all_raw(&buf[..n])?;
+            throttle.pace(n);
+            n = read_full(&mut src, &mut buf).map_err(|e| util::file_error(local, e))?;
+        }
+        dst.setstat(uploaded_stat(&meta).with_size(meta.len()))?;
+        Ok(resumed)
+    }
+
+    /// Upload the local file at `local` to `remote` like `upload_path`, but
+    /// without sending the runs of zeros it contains, returning how many
+    /// bytes were actually sent.
+    ///
+    /// The file is scanned in blocks of 4 KiB, and the handle is seeked past
+    /// those which only hold zeros; the size is then set to that of `local`,
+    /// so that trailing zeros aren't lost either. Whether the skipped ranges
+    /// become holes on the remote filesystem is up to the server, which
+    /// could also allocate them; they read back as zeros either way. Unlike
+    /// `upload_path`, `local` is always followed if it is a symlink.
+    pub fn upload_sparse(&self, local: &Path, remote: &Path) -> Result<u64, Error> {
+        let meta = fs::metadata(local).map_err(|e| util::file_error(local, e))?;
+        let mut src = fs::File::open(local).map_err(|e| util::file_error(local, e))?;
+        let mut dst = self.open_mode(
+            remote,
+            OpenFlags::WRITE | OpenFlags::TRUNCATE,
+            local_perm(&meta) as i32,
+            OpenType::File,
+        )?;
+
+        let mut throttle = self.throttle();
+        let mut buf = vec![0; throttle.buf_size()];
+        let mut offset = 0;
+        let mut sent = 0;
+        loop {
+            let n = read_full(&mut src, &mut buf).map_err(|e| util::file_error(local, e))?;
+            if n == 0 {
+                break;
+            }
+            // Send each run of blocks holding data in one go
+            let mut run_start = None;
+            for (i, block) in buf[..n].chunks(SPARSE_BLOCK_SIZE).enumerate() {
+                let start = i * SPARSE_BLOCK_SIZE;
+                let zero = block.iter().all(|b| *b == 0);
+                match (zero, run_start) {
+                    (false, None) => run_start = Some(start),
+                    (true, Some(run)) => {
+                        dst.seek_raw(offset + run as u64)?;
+                        dst.write_all_raw(&buf[run..start])?;
+                        throttle.pace(start - run);
+                        sent += (start - run) as u64;
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(run) = run_start {
+                dst.seek_raw(offset + run as u64)?;
+                dst.write_all_raw(&buf[run..n])?;
+                throttle.pace(n - run);
+                sent += (n - run) as u64;
+            }
+            offset += n as u64;
+        }
+        dst.setstat(uploaded_stat(&meta).with_size(meta.len()))?;
+        Ok(sent)
+    }
+
+    /// Download the remote file at `remote` to `local`, giving it the same
+    /// permissions and timestamps.
+    ///
+    /// This is the counterpart of `upload_path`: an existing local file is
+    /// overwritten, and on unix a remote symlink is recreated as a local
+    /// symlink with the same target. Elsewhere symlinks are followed.
+    pub fn download_path(&self, remote: &Path, local: &Path) -> Result<(), Error> {
+        self.download(remote, local, false)
+    }
+
+    /// Like `download_path`, but checking that as many bytes were received
+    /// as the size the server gave for the file when it was opened.
+    ///
+    /// A transfer cut short, or a file which changed size meanwhile, is
+    /// reported with a `LIBSSH2_ERROR_FILE` error saying that the sizes
+    /// don't match, once the local file is written. This is no substitute
+    /// for a checksum, as it can't tell whether the data itself is right.
+    /// Servers sending no size, and symlinks, are not checked.
+    pub fn download_path_verified(&self, remote: &Path, local: &Path) -> Result<(), Error> {
+        self.download(remote, local, true)
+    }
+
+    /// The pacing of a transfer starting now, as set by `set_transfer_rate`.
+    fn throttle(&self) -> Throttle {
+        let rate = self
+            .inner()
+            .and_then(|sftp_inner| *sftp_inner.rate_limit.lock());
+        Throttle::new(rate)
+    }
+
+    fn download(&self, remote: &Path, local: &Path, verify: bool) -> Result<(), Error> {
+        #[cfg(unix)]
+        {
+            if self.lstat(remote)?.file_type().is_symlink() {
+                let target = self.readlink(remote)?;
+                // Like `File::create` below, replace whatever is there
+                // already, but leave a directory for `symlink` to fail on.
+                match fs::symlink_metadata(local) {
+                    Ok(ref meta) if !meta.is_dir() => {
+                        fs::remove_file(local).map_err(|e| util::file_error(local, e))?
+                    }
+                    _ => {}
+                }
+                return ::std::os::unix::fs::symlink(&target, local)
+                    .map_err(|e| util::file_error(local, e));
+            }
+        }
+
+        let mut src = self.open(remote)?;
+        let stat = src.stat()?;
+        let mut dst = fs::File::create(local).map_err(|e| util::file_error(local, e))?;
+        let mut throttle = self.throttle();
+        let mut buf = vec![0; throttle.buf_size()];
+        let mut received = 0;
+        loop {
+            match src.read_raw(&mut buf)? {
+                0 => break,
+                n => {
+                    dst.write_all(&buf[..n])
+                        .map_err(|e| util::file_error(local, e))?;
+                    throttle.pace(n);
+                    received += n as u64;
+                }
+            }
+        }
+        drop(dst);
+        match stat.size {
+            Some(size) if verify && size != received => {
+                return Err(Error::with_message(
+                    raw::LIBSSH2_ERROR_FILE,
+                    format!(
+                        "{}: size mismatch, received {} bytes out of {}",
+                        remote.display(),
+                        received,
+                        size
+                    ),
+                ))
+            }
+            _ => {}
+        }
+
+        if let (Some(atime), Some(mtime)) = (stat.atime, stat.mtime) {
+            filetime::set_file_times(
+                local,
+                FileTime::from_unix_time(atime as i64, 0),
+                FileTime::from_unix_time(mtime as i64, 0),
+            )
+            .map_err(|e| util::file_error(local, e))?;
+        }
+        if let Some(perm) = stat.perm {
+            fs::set_permissions(local, local_permissions(local, perm)?)
+                .map_err(|e| util::file_error(local, e))?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader streaming the tree under the remote directory `root`
+    /// as a tar archive.
+    ///
+    /// See `ArchiveReader` for the format of the archive.
+    pub fn archive_reader(&self, root: &Path) -> ArchiveReader {
+        ArchiveReader::new(self.clone(), root)
+    }
+
+    /// Returns an iterator over the files and directories under the remote
+    /// directory `root`, see `Walk`.
+    pub fn walk(&self, root: &Path) -> Walk {
+        Walk::new(self.clone(), root)
+    }
+
+    /// Returns a one-way mirror of the remote directory `remote` into the
+    /// local directory `local`, which is created if needed.
+    ///
+    /// Nothing is done until `Mirror::step` or `Mirror::run` is called; see
+    /// `Mirror` for what is copied and how to resume an interrupted mirror.
+    pub fn mirror(&self, remote: &Path, local: &Path) -> Mirror {
+        Mirror::new(self.clone(), remote, local)
+    }
+
+    /// Returns the underlying libssh2 SFTP pointer, or null once shut down.
+    ///
+    /// This is for interoperating with other code driving libssh2 directly.
+    /// The pointer stays owned by this `Sftp`: it must not be shut down, not
+    /// be used after this `Sftp` and all its clones and files are dropped,
+    /// and libssh2 must only be called with it while holding the session
+    /// lock (see `Session::raw`).
+    #[doc(hidden)]
+    pub fn raw(&self) -> *mut raw::LIBSSH2_SFTP {
+        match self.inner.as_ref().and_then(|wrapper| wrapper.0.as_ref()) {
+            Some(sftp_inner) => sftp_inner.raw,
+            None => ptr::null_mut(),
+        }
+    }
+
+    // This method is used by the async ssh crate
+    #[doc(hidden)]
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        // We cannot shutdown the SFTP if files are still open, etc, as these store a ref to the sftp in libssh2.
+        // We have to make sure we are the last reference to it.
+        let res = match self.inner.as_mut() {
+            Some(sftp_inner_arc) => match Arc::get_mut(sftp_inner_arc) {
+                Some(sftp_inner_wrapper) => {
+                    let res = {
+                        let sftp_inner = sftp_inner_wrapper.0.as_ref().expect(
+                            "We were holding an Arc<SftpInnerDropWrapper>, \
+                                so nobody could unset this (set on creation)",
+                        );
+                        sftp_inner
+                            .sess
+                            .lock()
+                            .rc(unsafe { raw::libssh2_sftp_shutdown(sftp_inner.raw) })
+                    };
+                    match res {
+                        // Nothing was torn down yet on a non-blocking session: keep this
+                        // object usable so that the call can be retried
+                        Err(ref e) if e.code() == raw::LIBSSH2_ERROR_EAGAIN => return res,
+                        // Early drop
+                        _ => sftp_inner_wrapper.0 = None,
+                    }
+                    res
+                }
+                None => {
+                    // We are failing shutdown as there are files left open, keep this object usable
+                    return Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE));
+                }
+            },
+            None => {
+                // We have already shut this down. Shutting down twice is a mistake from the caller code
+                return Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE));
+            }
+        };
+        self.inner = None;
+        res
+    }
+}
+
+/// A set of SFTP channels, each on its own session, to share work between.
+///
+/// As operations on one session are serialized, fanning out work (such as a
+/// recursive copy) across threads only pays off when those threads talk over
+/// distinct connections. The pool does not establish the sessions itself:
+/// connect and authenticate them as usual, then hand their `Sftp` channels
+/// over to `SftpPool::new`.
+///
+/// This is also the way to fetch many small files without paying a round
+/// trip per request. libssh2 keeps the state of an open, read or close in
+/// progress on the SFTP channel, so those of several files can't be
+/// interleaved on one channel, and the channels of one session can't be
+/// driven at once either: in non-blocking mode, libssh2 refuses to send a
+/// packet while another one is partly written to the socket. Requests
+/// from one thread per channel of the pool are in flight together.
+pub struct SftpPool {
+    sftps: Vec<Sftp>,
+    next: AtomicUsize,
+}
+
+impl SftpPool {
+    /// Creates a pool out of the given channels.
+    ///
+    /// Channels opened on the same session should not be given here, as they
+    /// would contend with each other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sftps` is empty.
+    pub fn new(sftps: Vec<Sftp>) -> SftpPool {
+        assert!(!sftps.is_empty(), "an SftpPool needs at least one channel");
+        SftpPool {
+            sftps,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Picks a channel to run the next operation on.
+    ///
+    /// Channels are handed out in turn, skipping over those whose session is
+    /// busy with another operation at this instant. If all of them are busy,
+    /// the next one in line is returned anyway.
+    pub fn get(&self) -> &Sftp {
+        let len = self.sftps.len();
+        // Reduced first, as adding to a counter which has wrapped around
+        // would overflow
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        (0..len)
+            .map(|i| &self.sftps[(start + i) % len])
+            .find(|sftp| !sftp.is_busy())
+            .unwrap_or(&self.sftps[start])
+    }
+
+    /// Returns the number of channels in the pool, which is also the number
+    /// of operations that may run at the same time.
+    pub fn channels(&self) -> usize {
+        self.sftps.len()
+    }
+
+    /// Consumes the pool, returning its channels.
+    pub fn into_inner(self) -> Vec<Sftp> {
+        self.sftps
+    }
+}
+
+/// An `Sftp` channel remembering the metadata of the paths it was asked
+/// about, created by `Sftp::with_stat_cache`.
+///
+/// The server never tells when a file changes, so a cached `FileStat` is
+/// returned as is, however old, until the path is invalidated or evicted:
+/// changes made by other clients, by other handles, or by operations run
+/// on `sftp()` itself are not seen. Call `invalidate` after modifying a
+/// path, or `clear`, and only rely on the cache for trees which are not
+/// being modified. Invalidating a directory leaves its entries cached, as
+/// well as the paths leading through a symbolic link to it.
+///
+/// Once `capacity` paths are cached, the least recently used one is
+/// evicted. Failures are not cached.
+pub struct StatCache {
+    sftp: Sftp,
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+struct CacheEntries {
+    // Keyed by the path and whether symbolic links were followed, along
+    // with the tick of the last use of each path
+    stats: HashMap<(PathBuf, bool), (FileStat, u64)>,
+    order: BTreeMap<u64, (PathBuf, bool)>,
+    tick: u64,
+    // Bumped whenever paths are forgotten, so that a stat which was under
+    // way meanwhile isn't cached
+    generation: u64,
+}
+
+impl StatCache {
+    /// Like `Sftp::stat`, answered from the cache when possible.
+    pub fn stat(&self, filename: &Path) -> Result<FileStat, Error> {
+        self.cached(filename, true, |sftp| sftp.stat(filename))
+    }
+
+    /// Like `Sftp::lstat`, answered from the cache when possible.
+    pub fn lstat(&self, filename: &Path) -> Result<FileStat, Error> {
+        self.cached(filename, false, |sftp| sftp.lstat(filename))
+    }
+
+    /// Forgets what is known about `filename`, so that it is asked to the
+    /// server again next time.
+    pub fn invalidate(&self, filename: &Path) {
+        let mut entries = self.entries.lock();
+        entries.generation += 1;
+        for &follow in &[true, false] {
+            entries.remove(&(filename.to_path_buf(), follow));
+        }
+    }
+
+    /// Forgets every cached path.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock();
+        entries.generation += 1;
+        entries.stats.clear();
+        entries.order.clear();
+    }
+
+    /// The channel used for the requests, to run the other operations.
+    pub fn sftp(&self) -> &Sftp {
+        &self.sftp
+    }
+
+    fn cached<F>(&self, filename: &Path, follow: bool, fetch: F) -> Result<FileStat, Error>
+    where
+        F: FnOnce(&Sftp) -> Result<FileStat, Error>,
+    {
+        let key = (filename.to_path_buf(), follow);
+        let generation = {
+            let mut entries = self.entries.lock();
+            if let Some(stat) = entries.get(&key) {
+                return Ok(stat);
+            }
+            entries.generation
+        };
+        // The cache isn't kept locked over the round trip, which lets other
+        // threads use it meanwhile
+        let stat = fetch(&self.sftp)?;
+        let mut entries = self.entries.lock();
+        // The answer may predate a change the path was invalidated for
+        if self.capacity > 0 && entries.generation == generation {
+            entries.insert(key, stat.clone(), self.capacity);
+        }
+        Ok(stat)
+    }
+}
+
+impl CacheEntries {
+    fn get(&mut self, key: &(PathBuf, bool)) -> Option<FileStat> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.stats.get_mut(key)?;
+        let _ = self.order.remove(&entry.1);
+        let _ = self.order.insert(tick, key.clone());
+        entry.1 = tick;
+        Some(entry.0.clone())
+    }
+
+    fn insert(&mut self, key: (PathBuf, bool), stat: FileStat, capacity: usize) {
+        self.remove(&key);
+        while self.stats.len() >= capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                let _ = self.stats.remove(&key);
+            }
+        }
+        self.tick += 1;
+        let _ = self.order.insert(self.tick, key.clone());
+        let _ = self.stats.insert(key, (stat, self.tick));
+    }
+
+    fn remove(&mut self, key: &(PathBuf, bool)) {
+        if let Some((_, tick)) = self.stats.remove(key) {
+            let _ = self.order.remove(&tick);
+        }
+    }
+}
+
+impl Drop for SftpInnerDropWrapper {
+    fn drop(&mut self) {
+        // Check we were not early-dropped
+        if let Some(inner) = self.0.take() {
+            let sess = inner.sess.lock();
+            // The shutdown statement can go wrong and return an error code, but we are too late
+            // in the execution to recover it.
+            let _shutdown_result =
+                blocking_for_drop(&sess, || unsafe { raw::libssh2_sftp_shutdown(inner.raw) });
+        }
+    }
+}
+
+impl File {
+    /// Wraps a raw pointer in a new File structure tied to the lifetime of the
+    /// given session.
+    ///
+    /// This consumes ownership of `raw`.
+    unsafe fn from_raw(
+        sftp: &Sftp,
+        raw: *mut raw::LIBSSH2_SFTP_HANDLE,
+        path: PathBuf,
+        flags: OpenFlags,
+        open_type: OpenType,
+    ) -> File {
+        let sftp = sftp
+            .inner
+            .as_ref()
+            .expect("Cannot open file after sftp shutdown");
+        let raw = Arc::new(AtomicPtr::new(raw));
+        if let Some(ref sftp_inner) = sftp.0 {
+            let mut handles = sftp_inner.handles.lock();
+            handles.retain(|handle| handle.upgrade().is_some());
+            handles.push(Arc::downgrade(&raw));
+        }
+        File {
+            inner: Some(FileInner {
+                raw,
+                sftp: Arc::clone(sftp),
+                path,
+                flags,
+                open_type,
+            }),
+            read_ahead: 0,
+            ahead: Vec::new(),
+            ahead_pos: 0,
+            last_error: Mutex::new(None),
+        }
+    }
+
+    /// Set the metadata for this handle.
+    pub fn setstat(&mut self, stat: FileStat) -> Result<(), Error> {
+        let locked = self.lock()?;
+        locked.session_rc(unsafe {
+            let mut raw = stat.raw();
+            raw::libssh2_sftp_fstat_ex(locked.raw, &mut raw, 1)
+        })
     }
 
     /// Get the metadata for this handle.
     pub fn stat(&mut self) -> Result<FileStat, Error> {
+        trace_span!("sftp_fstat");
         let locked = self.lock()?;
         unsafe {
             let mut ret = mem::zeroed();
@@ -520,120 +1950,553 @@ impl File {
         }
     }
 
-    /// Reads a block of data from a handle and returns file entry information
-    /// for the next entry, if any.
-    ///
-    /// Note that this provides raw access to the `readdir` function from
-    /// libssh2. This will return an error when there are no more files to
-    /// read, and files such as `.` and `..` will be included in the return
-    /// values.
-    ///
-    /// Also note that the return paths will not be absolute paths, they are
-    /// the filenames of the files in this directory.
-    pub fn readdir(&mut self) -> Result<(PathBuf, FileStat), Error> {
+    /// Reads a block of data from a handle and returns file entry information
+    /// for the next entry, if any.
+    ///
+    /// Note that this provides raw access to the `readdir` function from
+    /// libssh2. This will return an error when there are no more files to
+    /// read, and files such as `.` and `..` will be included in the return
+    /// values.
+    ///
+    /// Also note that the return paths will not be absolute paths, they are
+    /// the filenames of the files in this directory.
+    pub fn readdir(&mut self) -> Result<(PathBuf, FileStat), Error> {
+        let mut stat = unsafe { mem::zeroed() };
+        let filename = self.readdir_ex(None, &mut stat)?;
+        Ok((filename, FileStat::from_raw(&stat)))
+    }
+
+    /// Like `readdir`, but returns `Ok(None)` once the end of the directory
+    /// is reached, so that errors are only used for actual failures.
+    pub fn next_entry(&mut self) -> Result<Option<(PathBuf, FileStat)>, Error> {
+        match self.readdir() {
+            Ok(entry) => Ok(Some(entry)),
+            Err(ref e) if e.code() == raw::LIBSSH2_ERROR_FILE => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `readdir`, but only returns the filename of the next entry.
+    ///
+    /// The attributes sent by the server along with each entry are not
+    /// converted into a `FileStat`, which makes this cheaper when only a
+    /// listing of names is needed.
+    pub fn readdir_names(&mut self) -> Result<PathBuf, Error> {
+        self.readdir_ex(None, ptr::null_mut())
+    }
+
+    /// Like `readdir`, but also returns the "longname" of the entry.
+    ///
+    /// The longname is an `ls -l` style line produced by the server. Its
+    /// format is unspecified by the protocol and it is only meant to be
+    /// displayed, but it typically contains the symbolic owner and group
+    /// names which aren't otherwise available.
+    pub fn readdir_with_longname(&mut self) -> Result<(PathBuf, String, FileStat), Error> {
+        let mut longentry = Vec::new();
+        let mut stat = unsafe { mem::zeroed() };
+        let filename = self.readdir_ex(Some(&mut longentry), &mut stat)?;
+        let longname = String::from_utf8_lossy(&longentry).into_owned();
+        Ok((filename, longname, FileStat::from_raw(&stat)))
+    }
+
+    fn readdir_ex(
+        &mut self,
+        mut longentry: Option<&mut Vec<u8>>,
+        stat: *mut raw::LIBSSH2_SFTP_ATTRIBUTES,
+    ) -> Result<PathBuf, Error> {
+        trace_span!("sftp_readdir");
+        let locked = self.lock()?;
+
+        let mut buf = Vec::<u8>::with_capacity(128);
+        if let Some(ref mut longentry) = longentry {
+            // libssh2 nul-terminates the longname rather than returning its
+            // length, so start from a zeroed buffer to be able to find it.
+            longentry.clear();
+            longentry.resize(128, 0);
+        }
+        let mut rc;
+        loop {
+            let (long_ptr, long_len) = match longentry {
+                Some(ref mut v) => (v.as_mut_ptr(), v.len()),
+                None => (ptr::null_mut(), 0),
+            };
+            rc = unsafe {
+                raw::libssh2_sftp_readdir_ex(
+                    locked.raw,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.capacity() as size_t,
+                    long_ptr as *mut _,
+                    long_len as size_t,
+                    stat,
+                )
+            };
+            if rc == raw::LIBSSH2_ERROR_BUFFER_TOO_SMALL {
+                // We can't tell which of the two buffers was too small
+                let path = match self.inner {
+                    Some(ref file_inner) => file_inner.path.as_path(),
+                    None => Path::new(""),
+                };
+                grow_name_buf(&mut buf, path)?;
+                if let Some(ref mut v) = longentry {
+                    let len = v.len();
+                    v.resize(len * 2, 0);
+                }
+            } else {
+                break;
+            }
+        }
+        if rc < 0 {
+            locked.notify(rc);
+            return Err(Error::from_session_error_raw(locked.sess.raw, rc));
+        } else if rc == 0 {
+            return Err(Error::new(raw::LIBSSH2_ERROR_FILE, "no more files"));
+        } else {
+            unsafe {
+                buf.set_len(rc as usize);
+            }
+        }
+        if let Some(v) = longentry {
+            let len = v.iter().position(|b| *b == 0).unwrap_or(v.len());
+            v.truncate(len);
+        }
+        Ok(mkpath(buf))
+    }
+
+    /// This function causes the remote server to synchronize the file data and
+    /// metadata to disk (like fsync(2)).
+    ///
+    /// For this to work requires fsync@openssh.com support on the server.
+    pub fn fsync(&mut self) -> Result<(), Error> {
+        let locked = self.lock()?;
+        locked
+            .sess
+            .rc(unsafe { raw::libssh2_sftp_fsync(locked.raw) })
+    }
+
+    /// Waits until the data written to this file is on stable storage on the
+    /// server, like `std::fs::File::sync_all`.
+    ///
+    /// This is `fsync`, except that servers without the fsync@openssh.com
+    /// extension are reported with a `LIBSSH2_FX_OP_UNSUPPORTED` error
+    /// saying so, rather than a generic protocol error. Durability can then
+    /// not be guaranteed at all over this connection.
+    pub fn sync_all(&mut self) -> Result<(), Error> {
+        let locked = self.lock()?;
+        let rc = unsafe { raw::libssh2_sftp_fsync(locked.raw) };
+        if rc == raw::LIBSSH2_ERROR_SFTP_PROTOCOL {
+            let status = unsafe { raw::libssh2_sftp_last_error(locked.sftp) } as c_int;
+            if status == raw::LIBSSH2_FX_OP_UNSUPPORTED {
+                return Err(Error::new(
+                    status,
+                    "the server does not support fsync@openssh.com, \
+                     data cannot be synchronized to disk",
+                ));
+            }
+            return Err(Error::from_errno(status));
+        }
+        locked.session_rc(rc)
+    }
+
+    /// Waits for the server to acknowledge the writes libssh2 sent for this
+    /// handle but hasn't reported as written yet. This says nothing about
+    /// durability, which is what `sync_all` is for.
+    ///
+    /// libssh2 splits a write into several packets sent at once, and
+    /// returns as soon as the first of them are acknowledged, so a short
+    /// write, or one which failed with `LIBSSH2_ERROR_EAGAIN` in
+    /// non-blocking mode, can leave the rest in flight. Once all writes
+    /// have been completed, as with `write_all`, nothing is left over and
+    /// this returns without a round trip.
+    ///
+    /// The acknowledged data is not reported by this call; the file pointer
+    /// moves past it, though, and libssh2 still counts it as written when
+    /// the rest of the interrupted write is handed to `write` again, as it
+    /// must be. `Write::flush` calls this.
+    pub fn flush_buffers(&mut self) -> Result<(), Error> {
+        let locked = self.lock()?;
+        loop {
+            // Each call waits for at least one packet in flight, if any, and
+            // the file pointer moves past those which were acknowledged
+            let before = unsafe { raw::libssh2_sftp_tell64(locked.raw) };
+            let rc = unsafe { raw::libssh2_sftp_write(locked.raw, b"".as_ptr() as *const _, 0) };
+            if rc < 0 {
+                if locked.sess.interrupted(rc as c_int) {
+                    continue;
+                }
+                locked.notify(rc as c_int);
+                return Err(self.failed(&locked, rc as c_int));
+            }
+            if unsafe { raw::libssh2_sftp_tell64(locked.raw) } == before {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Opens another handle to the same path, with the flags this one was
+    /// opened with.
+    ///
+    /// The new handle has its own file pointer, starting at the beginning of
+    /// the file, and the same read-ahead setting. It is reopened without
+    /// `OpenFlags::CREATE`, `TRUNCATE` and `EXCLUSIVE`, so that the data
+    /// isn't wiped nor the call rejected because the file now exists; it
+    /// fails if the file was removed in the meantime. As the handle refers
+    /// to the path, a file renamed over it since is what gets opened.
+    pub fn reopen(&self) -> Result<File, Error> {
+        let file_inner = match self.inner {
+            Some(ref file_inner) => file_inner,
+            None => return Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+        };
+        let sftp = Sftp {
+            inner: Some(Arc::clone(&file_inner.sftp)),
+            default_mode: DEFAULT_MODE,
+            cwd: None,
+        };
+        let flags = file_inner.flags - OpenFlags::TRUNCATE - OpenFlags::EXCLUSIVE;
+        let mut file = sftp.open_mode(&file_inner.path, flags, 0, file_inner.open_type)?;
+        file.read_ahead = self.read_ahead;
+        Ok(file)
+    }
+
+    /// The error behind the last failed read or write on this handle, if
+    /// any, including those done through `Read` and `Write`.
+    ///
+    /// This spares digging the `Error` out of the `io::Error` those traits
+    /// return. When the server rejected the request, the error holds the
+    /// SFTP status it answered with, such as `LIBSSH2_FX_PERMISSION_DENIED`,
+    /// rather than libssh2's generic `LIBSSH2_ERROR_SFTP_PROTOCOL`. The
+    /// error is kept until the next failure; successful calls don't clear it.
+    pub fn last_error(&self) -> Option<Error> {
+        self.last_error
+            .lock()
+            .as_ref()
+            .map(|err| Error::with_message(err.code(), err.message().to_string()))
+    }
+
+    /// Whether this handle was opened with `OpenType::Dir`, as by
+    /// `Sftp::opendir`, so that it is read with `readdir` rather than
+    /// `Read`. A closed handle is neither.
+    pub fn is_dir_handle(&self) -> bool {
+        match self.inner {
+            Some(ref file_inner) => match file_inner.open_type {
+                OpenType::Dir => true,
+                OpenType::File => false,
+            },
+            None => false,
+        }
+    }
+
+    /// The session this file was opened on, unless it is closed.
+    pub(crate) fn session(&self) -> Option<Arc<Mutex<SessionInner>>> {
+        let file_inner = self.inner.as_ref()?;
+        let sftp_inner = file_inner.sftp.0.as_ref()?;
+        Some(Arc::clone(&sftp_inner.sess))
+    }
+
+    fn lock(&self) -> Result<LockedFile, Error> {
+        match self.inner.as_ref() {
+            Some(file_inner) => {
+                let sftp_inner = file_inner.sftp.0.as_ref().expect(
+                    "We are holding an Arc<SftpInnerDropWrapper>, \
+                        so nobody could unset this (set on creation)",
+                );
+                let sess = sftp_inner.sess.lock();
+                let raw = file_inner.raw.load(Ordering::Relaxed);
+                if raw.is_null() {
+                    // Closed by `Sftp::close_all`
+                    return Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE));
+                }
+                Ok(LockedFile {
+                    sess,
+                    raw,
+                    sftp: sftp_inner.raw,
+                    bytes_read: &sftp_inner.bytes_read,
+                    bytes_written: &sftp_inner.bytes_written,
+                    would_block: &sftp_inner.would_block,
+                })
+            }
+            None => Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+        }
+    }
+
+    /// Returns the current position of this handle's file pointer.
+    ///
+    /// Like seeking, this is answered locally without talking to the server,
+    /// and reflects the data that has been read or written so far.
+    pub fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.position()?)
+    }
+
+    /// Reads from `offset` in the file, leaving the file pointer used by
+    /// `Read` and `Write` where it was, like `pread(2)`.
+    ///
+    /// libssh2 has no positioned reads, so the handle is seeked to `offset`
+    /// before the read, and back afterwards; data read ahead is dropped. As
+    /// with `Read::read`, fewer bytes than asked for may be returned, and 0
+    /// means that `offset` is at or past the end of the file. Use one `File`
+    /// per worker to read different ranges of a file in parallel.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        let pos = self.position()?;
+        self.ahead.clear();
+        self.ahead_pos = 0;
+        self.seek_raw(offset)?;
+        let res = self.read_raw(buf);
+        self.seek_raw(pos)?;
+        res
+    }
+
+    /// Writes at `offset` in the file, leaving the file pointer used by
+    /// `Read` and `Write` where it was, like `pwrite(2)`.
+    ///
+    /// As with `read_at`, each call seeks the handle to `offset` before
+    /// writing and back afterwards, and may write fewer bytes than given.
+    /// Writing past the end of the file extends it, the gap reading back as
+    /// zeros on most servers.
+    ///
+    /// Several uploaders can each write their own range of a file through
+    /// their own `File`. The server applies the writes in the order it
+    /// receives them, with no locking: ranges which overlap end up with
+    /// whichever data came last. On a file opened with `OpenFlags::APPEND`,
+    /// OpenSSH ignores the offset and appends.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<usize, Error> {
+        let pos = self.position()?;
+        self.ahead.clear();
+        self.ahead_pos = 0;
+        self.seek_raw(offset)?;
+        let res = self.write_raw(buf);
+        self.seek_raw(pos)?;
+        res
+    }
+
+    /// Fills `buf` entirely from the current position, for reading a file
+    /// whose size is known into a preallocated buffer in one go.
+    ///
+    /// libssh2 keeps as many read requests in flight as fit in the buffer
+    /// it is given, so the whole remainder of `buf` is handed to it at once
+    /// rather than in the pieces `Read::read_exact` may use. Data already
+    /// read ahead is used first. If the end of the file is reached before
+    /// `buf` is full, an error with code `LIBSSH2_ERROR_FILE` is returned,
+    /// and the contents of `buf` and the position are unspecified.
+    pub fn read_exact_fast(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let buffered = cmp::min(buf.len(), self.ahead.len() - self.ahead_pos);
+        buf[..buffered].copy_from_slice(&self.ahead[self.ahead_pos..self.ahead_pos + buffered]);
+        self.ahead_pos += buffered;
+        if self.read_full_raw(&mut buf[buffered..])? < buf.len() - buffered {
+            return Err(Error::new(
+                raw::LIBSSH2_ERROR_FILE,
+                "end of file reached before the buffer was filled",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The position of the file pointer, as seen by the caller.
+    fn position(&self) -> Result<u64, Error> {
+        let buffered = (self.ahead.len() - self.ahead_pos) as u64;
+        let locked = self.lock()?;
+        Ok(unsafe { raw::libssh2_sftp_tell64(locked.raw) } - buffered)
+    }
+
+    /// Sets the minimum amount of data, in bytes, requested from the server
+    /// by each read on this handle.
+    ///
+    /// libssh2 has no separate prefetch setting: it keeps as many read
+    /// requests in flight as needed to fill the buffer it is given. Reads
+    /// smaller than `bytes` are therefore served from an internal buffer
+    /// of that size, which keeps the link busy when streaming a large file
+    /// in small pieces. The default of 0 passes reads through unchanged.
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.read_ahead = bytes;
+    }
+
+    /// Reads from the read-ahead buffer, filling it first if `buf` is
+    /// smaller than it.
+    fn read_buffered(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ahead_pos == self.ahead.len() && buf.len() < self.read_ahead {
+            let mut ahead = Vec::new();
+            mem::swap(&mut ahead, &mut self.ahead);
+            ahead.resize(self.read_ahead, 0);
+            let res = self.read_raw(&mut ahead);
+            ahead.truncate(*res.as_ref().unwrap_or(&0));
+            self.ahead = ahead;
+            self.ahead_pos = 0;
+            if res? == 0 {
+                return Ok(0);
+            }
+        }
+        if self.ahead_pos < self.ahead.len() {
+            let n = cmp::min(buf.len(), self.ahead.len() - self.ahead_pos);
+            buf[..n].copy_from_slice(&self.ahead[self.ahead_pos..self.ahead_pos + n]);
+            self.ahead_pos += n;
+            return Ok(n);
+        }
+        Ok(self.read_raw(buf)?)
+    }
+
+    /// Drops any data read ahead but not consumed yet, moving the file
+    /// pointer back to the position the caller has reached.
+    fn discard_read_ahead(&mut self) -> io::Result<()> {
+        if self.ahead_pos < self.ahead.len() {
+            let pos = self.stream_position()?;
+            let locked = self.lock()?;
+            unsafe { raw::libssh2_sftp_seek64(locked.raw, pos) }
+        }
+        self.ahead.clear();
+        self.ahead_pos = 0;
+        Ok(())
+    }
+
+    fn read_raw(&self, buf: &mut [u8]) -> Result<usize, Error> {
         let locked = self.lock()?;
+        loop {
+            let rc = unsafe {
+                raw::libssh2_sftp_read(locked.raw, buf.as_mut_ptr() as *mut _, buf.len() as size_t)
+            };
+            if rc >= 0 {
+                let _ = locked.bytes_read.fetch_add(rc as u64, Ordering::Relaxed);
+                return Ok(rc as usize);
+            } else if !locked.sess.interrupted(rc as c_int) {
+                locked.notify(rc as c_int);
+                return Err(self.failed(&locked, rc as c_int));
+            }
+        }
+    }
 
-        let mut buf = Vec::<u8>::with_capacity(128);
-        let mut stat = unsafe { mem::zeroed() };
-        let mut rc;
+    fn write_raw(&self, buf: &[u8]) -> Result<usize, Error> {
+        let locked = self.lock()?;
         loop {
-            rc = unsafe {
-                raw::libssh2_sftp_readdir_ex(
-                    locked.raw,
-                    buf.as_mut_ptr() as *mut _,
-                    buf.capacity() as size_t,
-                    0 as *mut _,
-                    0,
-                    &mut stat,
-                )
+            let rc = unsafe {
+                raw::libssh2_sftp_write(locked.raw, buf.as_ptr() as *const _, buf.len() as size_t)
             };
-            if rc == raw::LIBSSH2_ERROR_BUFFER_TOO_SMALL {
-                let cap = buf.capacity();
-                buf.reserve(cap);
-            } else {
-                break;
+            if rc >= 0 {
+                let _ = locked.bytes_written.fetch_add(rc as u64, Ordering::Relaxed);
+                return Ok(rc as usize);
+            } else if !locked.sess.interrupted(rc as c_int) {
+                locked.notify(rc as c_int);
+                return Err(self.failed(&locked, rc as c_int));
             }
         }
-        if rc < 0 {
-            return Err(Error::from_session_error_raw(locked.sess.raw, rc));
-        } else if rc == 0 {
-            return Err(Error::new(raw::LIBSSH2_ERROR_FILE, "no more files"));
-        } else {
-            unsafe {
-                buf.set_len(rc as usize);
+    }
+
+    /// Reads until `buf` is full or the end of the file is reached.
+    fn read_full_raw(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_raw(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
             }
         }
-        Ok((mkpath(buf), FileStat::from_raw(&stat)))
+        Ok(filled)
     }
 
-    /// This function causes the remote server to synchronize the file data and
-    /// metadata to disk (like fsync(2)).
-    ///
-    /// For this to work requires fsync@openssh.com support on the server.
-    pub fn fsync(&mut self) -> Result<(), Error> {
+    /// The error for a read or write which failed with `rc`, recorded for
+    /// `last_error` along with the server's status, which the next request
+    /// would overwrite.
+    fn failed(&self, locked: &LockedFile, rc: c_int) -> Error {
+        let err = Error::from_session_error_raw(locked.sess.raw, rc);
+        *self.last_error.lock() = Some(if rc == raw::LIBSSH2_ERROR_SFTP_PROTOCOL {
+            let status = unsafe { raw::libssh2_sftp_last_error(locked.sftp) };
+            Error::from_errno(status as c_int)
+        } else {
+            Error::with_message(err.code(), err.message().to_string())
+        });
+        err
+    }
+
+    /// Moves the file pointer, without the bookkeeping of `Seek`: the
+    /// read-ahead buffer must be empty.
+    fn seek_raw(&self, pos: u64) -> Result<(), Error> {
         let locked = self.lock()?;
-        locked
-            .sess
-            .rc(unsafe { raw::libssh2_sftp_fsync(locked.raw) })
+        unsafe { raw::libssh2_sftp_seek64(locked.raw, pos) }
+        Ok(())
     }
 
-    fn lock(&self) -> Result<LockedFile, Error> {
+    fn write_all_raw(&self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let n = self.write_raw(buf)?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Returns the underlying libssh2 handle pointer, or null once closed.
+    ///
+    /// The handle stays owned by this `File`: it must not be closed nor used
+    /// once this `File` is dropped, and, as with `Sftp::raw`, libssh2 must
+    /// only be called with it while the session is locked. Reading or
+    /// seeking through it also bypasses the read-ahead buffer of this `File`.
+    #[doc(hidden)]
+    pub fn raw_handle(&self) -> *mut raw::LIBSSH2_SFTP_HANDLE {
         match self.inner.as_ref() {
-            Some(file_inner) => {
-                let sftp_inner = file_inner.sftp.0.as_ref().expect(
-                    "We are holding an Arc<SftpInnerDropWrapper>, \
-                        so nobody could unset this (set on creation)",
-                );
-                let sess = sftp_inner.sess.lock();
-                Ok(LockedFile {
-                    sess,
-                    raw: file_inner.raw,
-                })
-            }
-            None => Err(Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE)),
+            Some(file_inner) => file_inner.raw.load(Ordering::Relaxed),
+            None => ptr::null_mut(),
         }
     }
 
     #[doc(hidden)]
     pub fn close(&mut self) -> Result<(), Error> {
-        {
+        let res = {
             let locked = self.lock()?;
-            Error::rc(unsafe { raw::libssh2_sftp_close_handle(locked.raw) })?;
+            locked.rc(unsafe { raw::libssh2_sftp_close_handle(locked.raw) })
+        };
+        match res {
+            // The request wasn't sent, because the session is non-blocking
+            // or the socket failed, and libssh2 still owns the handle: keep
+            // it so that the call can be retried, if only by `Drop`
+            Err(ref e)
+                if e.code() == raw::LIBSSH2_ERROR_EAGAIN
+                    || e.code() == raw::LIBSSH2_ERROR_SOCKET_SEND
+                    || e.code() == raw::LIBSSH2_ERROR_ALLOC => {}
+            // Otherwise the request was sent and libssh2 freed the handle,
+            // even if the server's reply is an error or never came
+            _ => self.inner = None,
         }
-        self.inner = None;
-        Ok(())
+        res
+    }
+
+    /// Closes the remote handle, reporting whether the server accepted it.
+    ///
+    /// Dropping a `File` also closes it, but any failure is then lost. Some
+    /// servers only report errors for the data written, such as a full disk
+    /// or quota, when the handle is closed: write paths should end with this
+    /// call. On failure the error carries the status code the server
+    /// answered with, such as `LIBSSH2_FX_NO_SPACE_ON_FILESYSTEM`.
+    ///
+    /// There is nothing to flush first, as writes are sent to the server
+    /// before they return. Like `Drop`, this blocks even if the session is
+    /// in non-blocking mode, since the file can't be handed back to retry.
+    pub fn close_checked(mut self) -> Result<(), Error> {
+        let sess = self
+            .session()
+            .ok_or_else(|| Error::from_errno(raw::LIBSSH2_ERROR_BAD_USE))?;
+        let was_blocking = sess.lock().is_blocking();
+        sess.lock().set_blocking(true);
+        let res = self.close();
+        sess.lock().set_blocking(was_blocking);
+        res
     }
 }
 
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let locked = self.lock()?;
-        unsafe {
-            let rc =
-                raw::libssh2_sftp_read(locked.raw, buf.as_mut_ptr() as *mut _, buf.len() as size_t);
-            if rc < 0 {
-                Err(Error::from_session_error_raw(locked.sess.raw, rc as _).into())
-            } else {
-                Ok(rc as usize)
-            }
-        }
+        trace_io_span!(span, "sftp_read", buf.len());
+        let res = self.read_buffered(buf);
+        trace_io_result!(span, res);
+        res
     }
 }
 
 impl Write for File {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let locked = self.lock()?;
-        let rc = unsafe {
-            raw::libssh2_sftp_write(locked.raw, buf.as_ptr() as *const _, buf.len() as size_t)
-        };
-        if rc < 0 {
-            Err(Error::from_session_error_raw(locked.sess.raw, rc as _).into())
-        } else {
-            Ok(rc as usize)
-        }
+        trace_io_span!(span, "sftp_write", buf.len());
+        let res = self
+            .discard_read_ahead()
+            .and_then(|()| self.write_raw(buf).map_err(io::Error::from));
+        trace_io_result!(span, res);
+        res
     }
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        Ok(self.flush_buffers()?)
     }
 }
 
@@ -651,11 +2514,7 @@ impl Seek for File {
     fn seek(&mut self, how: SeekFrom) -> io::Result<u64> {
         let next = match how {
             SeekFrom::Start(pos) => pos,
-            SeekFrom::Current(offset) => {
-                let locked = self.lock()?;
-                let cur = unsafe { raw::libssh2_sftp_tell64(locked.raw) };
-                (cur as i64 + offset) as u64
-            }
+            SeekFrom::Current(offset) => (self.stream_position()? as i64 + offset) as u64,
             SeekFrom::End(offset) => match self.stat() {
                 Ok(s) => match s.size {
                     Some(size) => (size as i64 + offset) as u64,
@@ -664,12 +2523,80 @@ impl Seek for File {
                 Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
             },
         };
+        self.ahead.clear();
+        self.ahead_pos = 0;
         let locked = self.lock()?;
         unsafe { raw::libssh2_sftp_seek64(locked.raw, next) }
         Ok(next)
     }
 }
 
+impl ReadFile {
+    /// Get the metadata for this handle, see `File::stat`.
+    pub fn stat(&mut self) -> Result<FileStat, Error> {
+        self.0.stat()
+    }
+
+    /// See `File::set_read_ahead`.
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.0.set_read_ahead(bytes)
+    }
+
+    /// Returns the underlying `File`.
+    pub fn into_inner(self) -> File {
+        self.0
+    }
+}
+
+impl Read for ReadFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for ReadFile {
+    fn seek(&mut self, how: SeekFrom) -> io::Result<u64> {
+        self.0.seek(how)
+    }
+}
+
+impl WriteFile {
+    /// Get the metadata for this handle, see `File::stat`.
+    pub fn stat(&mut self) -> Result<FileStat, Error> {
+        self.0.stat()
+    }
+
+    /// See `File::sync_all`.
+    pub fn sync_all(&mut self) -> Result<(), Error> {
+        self.0.sync_all()
+    }
+
+    /// See `File::flush_buffers`.
+    pub fn flush_buffers(&mut self) -> Result<(), Error> {
+        self.0.flush_buffers()
+    }
+
+    /// See `File::close_checked`.
+    pub fn close_checked(self) -> Result<(), Error> {
+        self.0.close_checked()
+    }
+
+    /// Returns the underlying `File`.
+    pub fn into_inner(self) -> File {
+        self.0
+    }
+}
+
+impl Write for WriteFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
 impl Drop for File {
     fn drop(&mut self) {
         // Set ssh2 to blocking if the file was not closed yet (by .close()).
@@ -679,18 +2606,76 @@ impl Drop for File {
                     so nobody could unset this (set on creation)",
             );
             let sess_inner = sftp_inner.sess.lock();
-            let was_blocking = sess_inner.is_blocking();
-            sess_inner.set_blocking(true);
+            let raw = file_inner.raw.swap(ptr::null_mut(), Ordering::Relaxed);
+            if raw.is_null() {
+                return;
+            }
             // The close statement can go wrong and return an error code, but we are too late
             // in the execution to recover it.
-            let _close_handle_result = unsafe { raw::libssh2_sftp_close_handle(file_inner.raw) };
-            sess_inner.set_blocking(was_blocking);
+            let _close_handle_result = blocking_for_drop(&sess_inner, || unsafe {
+                raw::libssh2_sftp_close_handle(raw)
+            });
         }
     }
 }
 
 impl FileStat {
+    /// Creates a stat with none of its fields set.
+    ///
+    /// Combined with the `with_*` methods this builds the payload for
+    /// `setstat`, where only the fields that are set get changed:
+    /// `FileStat::empty().with_perm(0o600)`.
+    pub fn empty() -> FileStat {
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    /// Sets the file size, in bytes.
+    pub fn with_size(mut self, size: u64) -> FileStat {
+        self.size = Some(size);
+        self
+    }
+
+    /// Sets the owner ID.
+    pub fn with_uid(mut self, uid: u32) -> FileStat {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Sets the owning group.
+    pub fn with_gid(mut self, gid: u32) -> FileStat {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Sets the permissions (mode).
+    pub fn with_perm(mut self, perm: u32) -> FileStat {
+        self.perm = Some(perm);
+        self
+    }
+
+    /// Sets the last access time.
+    pub fn with_atime(mut self, atime: u64) -> FileStat {
+        self.atime = Some(atime);
+        self
+    }
+
+    /// Sets the last modification time.
+    pub fn with_mtime(mut self, mtime: u64) -> FileStat {
+        self.mtime = Some(mtime);
+        self
+    }
+
     /// Returns the file type for this filestat.
+    ///
+    /// The type is taken from the `S_IFMT` bits of `perm`; if no permissions
+    /// were provided by the server the returned type matches nothing.
     pub fn file_type(&self) -> FileType {
         FileType {
             perm: self.perm.unwrap_or(0) as c_ulong,
@@ -707,6 +2692,32 @@ impl FileStat {
         self.file_type().is_file()
     }
 
+    /// Returns whether the file described by `other` is out of date compared
+    /// to this one, as a sync tool would decide whether to copy it again.
+    ///
+    /// The files are considered the same when both their size and
+    /// modification time are equal. Missing values never compare equal, so
+    /// that a copy is made when in doubt.
+    pub fn needs_update(&self, other: &FileStat) -> bool {
+        self.needs_update_within(other, 1)
+    }
+
+    /// Like `needs_update`, but tolerating modification times that are less
+    /// than `granularity` seconds apart.
+    ///
+    /// This is useful when one side rounds timestamps, e.g. FAT filesystems
+    /// which only store them to 2 seconds. A `granularity` of 0 is treated
+    /// as 1, which requires the times to be equal.
+    pub fn needs_update_within(&self, other: &FileStat, granularity: u64) -> bool {
+        match (self.size, other.size, self.mtime, other.mtime) {
+            (Some(a), Some(b), Some(c), Some(d)) if a == b => {
+                let diff = cmp::max(c, d) - cmp::min(c, d);
+                diff >= cmp::max(granularity, 1)
+            }
+            _ => true,
+        }
+    }
+
     /// Creates a new instance of a stat from a raw instance.
     pub fn from_raw(raw: &raw::LIBSSH2_SFTP_ATTRIBUTES) -> FileStat {
         fn val<T: Copy>(raw: &raw::LIBSSH2_SFTP_ATTRIBUTES, t: &T, flag: c_ulong) -> Option<T> {
@@ -727,6 +2738,16 @@ impl FileStat {
         }
     }
 
+    /// The `LIBSSH2_SFTP_ATTR_*` bits of the attributes which are set.
+    ///
+    /// For a stat received from the server this is the mask it sent, minus
+    /// `LIBSSH2_SFTP_ATTR_EXTENDED` whose pairs libssh2 drops: the owner and
+    /// group share a bit, as do both timestamps, and are only ever set
+    /// together when decoding.
+    pub fn present_flags(&self) -> u32 {
+        self.raw().flags as u32
+    }
+
     /// Convert this stat structure to its raw representation.
     pub fn raw(&self) -> raw::LIBSSH2_SFTP_ATTRIBUTES {
         fn flag<T>(o: &Option<T>, flag: c_ulong) -> c_ulong {
@@ -754,6 +2775,302 @@ impl FileStat {
     }
 }
 
+// `std::fs::Metadata` has no `is_empty` either
+#[allow(clippy::len_without_is_empty)]
+impl Metadata {
+    /// Returns the size of the file in bytes, or 0 if the server did not
+    /// send it.
+    pub fn len(&self) -> u64 {
+        self.stat.size.unwrap_or(0)
+    }
+
+    /// Returns the permission bits of the file, without the file type bits,
+    /// if the server sent them.
+    pub fn permissions(&self) -> Option<u32> {
+        self.stat.perm.map(|perm| perm & 0o7777)
+    }
+
+    /// Returns the type of the file.
+    pub fn file_type(&self) -> FileType {
+        self.stat.file_type()
+    }
+
+    /// Returns whether this metadata is for a directory.
+    pub fn is_dir(&self) -> bool {
+        self.stat.is_dir()
+    }
+
+    /// Returns whether this metadata is for a regular file.
+    pub fn is_file(&self) -> bool {
+        self.stat.is_file()
+    }
+
+    /// Returns whether this metadata is for a symbolic link.
+    ///
+    /// Like with `std::fs`, this can only be true for metadata obtained
+    /// with `Sftp::symlink_metadata`.
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
+    /// Returns the last modification time of the file.
+    ///
+    /// An error is returned if the server did not send it.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        time(self.stat.mtime)
+    }
+
+    /// Returns the last access time of the file.
+    ///
+    /// An error is returned if the server did not send it.
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        time(self.stat.atime)
+    }
+
+    /// Returns the underlying stat.
+    pub fn stat(&self) -> &FileStat {
+        &self.stat
+    }
+}
+
+impl From<FileStat> for Metadata {
+    fn from(stat: FileStat) -> Metadata {
+        Metadata { stat }
+    }
+}
+
+impl FsStat {
+    /// The total number of inodes of the filesystem, which is 0 when it
+    /// doesn't have a fixed number of them, as with btrfs.
+    pub fn total_inodes(&self) -> u64 {
+        self.raw.f_files
+    }
+
+    /// The number of inodes left for the server's user to create files
+    /// with, not counting those reserved for root.
+    pub fn free_inodes(&self) -> u64 {
+        self.raw.f_favail
+    }
+
+    /// Returns the underlying statvfs fields.
+    pub fn raw(&self) -> raw::LIBSSH2_SFTP_STATVFS {
+        self.raw
+    }
+}
+
+impl From<raw::LIBSSH2_SFTP_STATVFS> for FsStat {
+    fn from(raw: raw::LIBSSH2_SFTP_STATVFS) -> FsStat {
+        FsStat { raw }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Size of the buffers used by the transfer helpers: large enough for
+/// libssh2 to keep several requests in flight.
+const TRANSFER_BUF_SIZE: usize = 256 * 1024;
+
+/// The granularity at which `Sftp::upload_sparse` looks for zeros, the
+/// usual size of a filesystem block.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// A token bucket pacing a transfer to the rate set with
+/// `Sftp::set_transfer_rate`, if any.
+struct Throttle {
+    // Bytes per second, and how many bytes can be sent right away: up to
+    // one chunk, or less than nothing after a chunk sent too early
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    fn new(rate: Option<u64>) -> Throttle {
+        let mut throttle = Throttle {
+            rate: rate.map(|rate| cmp::max(rate, 1)),
+            tokens: 0.0,
+            refilled: Instant::now(),
+        };
+        throttle.tokens = throttle.buf_size() as f64;
+        throttle
+    }
+
+    /// The size of the chunks to transfer.
+    fn buf_size(&self) -> usize {
+        match self.rate {
+            Some(rate) => cmp::min(cmp::max(rate / 8, 1), TRANSFER_BUF_SIZE as u64) as usize,
+            None => TRANSFER_BUF_SIZE,
+        }
+    }
+
+    /// Accounts for `n` bytes just transferred, sleeping until the rate is
+    /// met again if they went over it.
+    fn pace(&mut self, n: usize) {
+        let rate = match self.rate {
+            Some(rate) => rate as f64,
+            None => return,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * rate).min(self.buf_size() as f64) - n as f64;
+        self.refilled = now;
+        if self.tokens < 0.0 {
+            let wait = -self.tokens / rate;
+            thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+            self.tokens = 0.0;
+            self.refilled = Instant::now();
+        }
+    }
+}
+
+/// The mode of the files created by `Sftp::create`, unless changed with
+/// `Sftp::set_default_mode`.
+const DEFAULT_MODE: i32 = 0o644;
+
+/// The size past which the buffers for names are not grown anymore.
+///
+/// A name has to fit in an SFTP packet, which libssh2 caps well below this,
+/// so a server still asking for more is misbehaving: growing the buffer
+/// forever would only exhaust memory.
+const MAX_NAME_BUF: usize = 1024 * 1024;
+
+/// Doubles the capacity of `buf`, which libssh2 reported too small for a
+/// name under `path`, dropping its contents.
+///
+/// Fails once the buffer reached `MAX_NAME_BUF`, which bounds the number of
+/// retries as well, or if the memory can't be had. `Vec::try_reserve` is
+/// too recent for the Rust versions supported, hence the allocation by
+/// hand.
+fn grow_name_buf(buf: &mut Vec<u8>, path: &Path) -> Result<(), Error> {
+    let cap = buf.capacity();
+    if cap >= MAX_NAME_BUF {
+        return Err(Error::with_message(
+            raw::LIBSSH2_ERROR_BUFFER_TOO_SMALL,
+            format!(
+                "name returned for {} is larger than {} bytes",
+                path.display(),
+                MAX_NAME_BUF
+            ),
+        ));
+    }
+    let new_cap = cmp::min(cap * 2, MAX_NAME_BUF);
+    let layout = Layout::from_size_align(new_cap, 1).expect("bounded by MAX_NAME_BUF");
+    let ptr = unsafe { alloc::alloc(layout) };
+    if ptr.is_null() {
+        return Err(Error::with_message(
+            raw::LIBSSH2_ERROR_ALLOC,
+            format!(
+                "out of memory for a name of {} bytes under {}",
+                new_cap,
+                path.display()
+            ),
+        ));
+    }
+    *buf = unsafe { Vec::from_raw_parts(ptr, 0, new_cap) };
+    Ok(())
+}
+
+/// The attributes to give to a file uploaded from a local one. Access and
+/// modification times can only be set together.
+fn uploaded_stat(meta: &fs::Metadata) -> FileStat {
+    let stat = FileStat::empty().with_perm(local_perm(meta));
+    let mtime = meta.modified().ok().and_then(unix_secs);
+    let atime = meta.accessed().ok().and_then(unix_secs).or(mtime);
+    match (atime, mtime) {
+        (Some(atime), Some(mtime)) => stat.with_atime(atime).with_mtime(mtime),
+        _ => stat,
+    }
+}
+
+/// Runs the callback set with `Sftp::on_would_block`, if `rc` reports that
+/// a call would block.
+fn notify_would_block(sess: &SessionInner, hook: &WouldBlockHook, rc: c_int) {
+    if rc != raw::LIBSSH2_ERROR_EAGAIN {
+        return;
+    }
+    if let Some(ref cb) = *hook.lock() {
+        let directions = unsafe { raw::libssh2_session_block_directions(sess.raw) };
+        cb(match directions {
+            raw::LIBSSH2_SESSION_BLOCK_INBOUND => BlockDirections::Inbound,
+            raw::LIBSSH2_SESSION_BLOCK_OUTBOUND => BlockDirections::Outbound,
+            0 => BlockDirections::None,
+            _ => BlockDirections::Both,
+        });
+    }
+}
+
+/// The longest a drop waits for the server, in milliseconds.
+const DROP_TIMEOUT_MS: c_long = 10_000;
+
+/// Runs `op` in blocking mode, as needed to release resources on drop, but
+/// with a timeout of at most `DROP_TIMEOUT_MS` so that a dead connection
+/// can't hang the thread. The mode and timeout of the session are restored
+/// afterwards.
+fn blocking_for_drop<F: FnOnce() -> c_int>(sess: &SessionInner, op: F) -> c_int {
+    let was_blocking = sess.is_blocking();
+    let timeout = unsafe { raw::libssh2_session_get_timeout(sess.raw) };
+    sess.set_blocking(true);
+    if timeout == 0 || timeout > DROP_TIMEOUT_MS {
+        unsafe { raw::libssh2_session_set_timeout(sess.raw, DROP_TIMEOUT_MS) }
+    }
+    let rc = op();
+    unsafe { raw::libssh2_session_set_timeout(sess.raw, timeout) }
+    sess.set_blocking(was_blocking);
+    rc
+}
+
+/// Like `read_full_raw`, for local files.
+fn read_full<R: Read>(src: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match src.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(unix)]
+fn local_perm(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+#[cfg(windows)]
+fn local_perm(meta: &fs::Metadata) -> u32 {
+    if meta.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn local_permissions(_path: &Path, perm: u32) -> Result<fs::Permissions, Error> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::Permissions::from_mode(perm & 0o7777))
+}
+#[cfg(windows)]
+fn local_permissions(path: &Path, perm: u32) -> Result<fs::Permissions, Error> {
+    let mut permissions = fs::metadata(path)
+        .map_err(|e| util::file_error(path, e))?
+        .permissions();
+    permissions.set_readonly(perm & 0o222 == 0);
+    Ok(permissions)
+}
+
+fn time(secs: Option<u64>) -> io::Result<SystemTime> {
+    match secs {
+        Some(secs) => Ok(UNIX_EPOCH + Duration::from_secs(secs)),
+        None => Err(util::io_other("time not provided by the server")),
+    }
+}
+
 impl FileType {
     /// Test whether this file type represents a directory.
     pub fn is_dir(&self) -> bool {
@@ -786,3 +3103,31 @@ fn mkpath(v: Vec<u8>) -> PathBuf {
     use std::str;
     PathBuf::from(str::from_utf8(&v).unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grow_name_buf_doubles_up_to_the_cap() {
+        let path = Path::new("foo");
+        let mut buf = Vec::with_capacity(128);
+        buf.push(1);
+        grow_name_buf(&mut buf, path).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(buf.capacity(), 256);
+
+        // A server which never stops asking for more
+        let mut retries = 1;
+        let err = loop {
+            match grow_name_buf(&mut buf, path) {
+                Ok(()) => retries += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(buf.capacity(), MAX_NAME_BUF);
+        assert_eq!(retries, 13);
+        assert_eq!(err.code(), raw::LIBSSH2_ERROR_BUFFER_TOO_SMALL);
+        assert!(err.message().contains("foo"), "{}", err.message());
+    }
+}
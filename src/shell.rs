@@ -0,0 +1,54 @@
+//! Quoting of the arguments of commands run with `Channel::exec`, which
+//! are interpreted by the remote user's shell.
+
+/// Quotes `arg` so that a POSIX shell reads it back as a single word,
+/// unchanged.
+///
+/// The argument is wrapped in single quotes, in which nothing is special
+/// but the single quote itself, which is written as `'\''`. Arguments made
+/// of characters which are never special, such as `foo/bar.txt`, are left
+/// as is for readability. `=` is not one of them: a leading one is expanded
+/// by zsh, and `FOO=bar` at the start of a command is an assignment.
+///
+/// The result is meant for `sh` and compatible shells, including `bash`
+/// and `zsh`. It is not safe for `csh`, nor for Windows servers, where the
+/// command line is parsed by another set of rules.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(ssh2::shell_quote("it's"), r#"'it'\''s'"#);
+/// ```
+pub fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "%+,-./:@_".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_string();
+    }
+    let mut out = String::with_capacity(arg.len() + 2);
+    out.push('\'');
+    for c in arg.chars() {
+        if c == '\'' {
+            out.push_str("'\\''");
+        } else {
+            out.push(c);
+        }
+    }
+    out.push('\'');
+    out
+}
+
+/// Quotes each of `args` with `shell_quote`, and joins them with spaces
+/// into a command line for `Channel::exec`.
+///
+/// # Example
+///
+/// ```
+/// let cmd = ssh2::shell_join(&["ls", "-l", "my file"]);
+/// assert_eq!(cmd, "ls -l 'my file'");
+/// ```
+pub fn shell_join(args: &[&str]) -> String {
+    args.iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,188 @@
+//! Carrying a session over a channel of another session, as done by
+//! `Session::connect_via`.
+//!
+//! libssh2 can only run a session over a socket, so a socket pair is made
+//! and a thread copies data between one end of it and the channel, while
+//! the new session is given the other end.
+
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::net::Shutdown;
+#[cfg(windows)]
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::thread;
+#[cfg(windows)]
+use std::time::Duration;
+
+use {Channel, Session};
+
+#[cfg(unix)]
+type Socket = UnixStream;
+#[cfg(windows)]
+type Socket = TcpStream;
+
+/// Size of the chunks data is copied in.
+const BUF_SIZE: usize = 32 * 1024;
+
+/// Starts copying data between `channel`, opened on `jump`, and a new
+/// socket, which is returned.
+pub(crate) fn spawn(jump: Session, channel: Channel) -> io::Result<Socket> {
+    let (ours, theirs) = socket_pair()?;
+    theirs.set_nonblocking(true)?;
+    let _ = thread::spawn(move || {
+        let mut pump = Pump {
+            jump,
+            channel,
+            socket: theirs,
+        };
+        let _ = pump.run();
+        let _ = pump.channel.close();
+    });
+    Ok(ours)
+}
+
+struct Pump {
+    jump: Session,
+    channel: Channel,
+    socket: Socket,
+}
+
+impl Pump {
+    /// Copies data both ways until the session on the socket is closed.
+    fn run(&mut self) -> io::Result<()> {
+        let mut buf = vec![0; BUF_SIZE];
+        // Data read from one side but not written to the other one yet
+        let mut up = Vec::new();
+        let mut down = Vec::new();
+        let mut channel_eof = false;
+        loop {
+            let mut progress = false;
+
+            if up.is_empty() {
+                match retry(self.socket.read(&mut buf))? {
+                    // The session over the tunnel is gone
+                    Some(0) => return Ok(()),
+                    Some(n) => up.extend_from_slice(&buf[..n]),
+                    None => {}
+                }
+            }
+            if !up.is_empty() {
+                if let Some(n) =
+                    retry(self.channel.write_nonblocking(&up).map_err(io::Error::from))?
+                {
+                    let _ = up.drain(..n);
+                    progress = true;
+                }
+            }
+
+            if down.is_empty() && !channel_eof {
+                match retry(
+                    self.channel
+                        .read_nonblocking(&mut buf)
+                        .map_err(io::Error::from),
+                )? {
+                    Some(0) => {
+                        // The target closed the connection: pass it on
+                        channel_eof = true;
+                        self.socket.shutdown(Shutdown::Write)?;
+                    }
+                    Some(n) => down.extend_from_slice(&buf[..n]),
+                    None => {}
+                }
+            }
+            if !down.is_empty() {
+                if let Some(n) = retry(self.socket.write(&down))? {
+                    let _ = down.drain(..n);
+                    progress = true;
+                }
+            }
+
+            if !progress {
+                let channel = !up.is_empty() || (down.is_empty() && !channel_eof);
+                self.wait(up.is_empty(), !down.is_empty(), channel)?;
+            }
+        }
+    }
+
+    /// Waits for the socket to be ready, or for `jump` to be if the channel
+    /// is being waited on.
+    #[cfg(unix)]
+    fn wait(&self, socket_read: bool, socket_write: bool, channel: bool) -> io::Result<()> {
+        let mut socket_events = 0;
+        if socket_read {
+            socket_events |= libc::POLLIN;
+        }
+        if socket_write {
+            socket_events |= libc::POLLOUT;
+        }
+        // libssh2 may also be waiting to send, e.g. for a window adjustment
+        let jump_events = match self.jump.block_directions() {
+            ::BlockDirections::Outbound | ::BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+            _ => libc::POLLIN,
+        };
+        let mut fds = [
+            libc::pollfd {
+                fd: self.socket.as_raw_fd(),
+                events: socket_events,
+                revents: 0,
+            },
+            libc::pollfd {
+                // A negative fd is ignored by poll
+                fd: if channel { self.jump.as_raw_fd() } else { -1 },
+                events: jump_events,
+                revents: 0,
+            },
+        ];
+        // libssh2 only gives up with EAGAIN once it has read all there is
+        // on its socket, so nothing is left buffered that waiting would miss
+        let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn wait(&self, _socket_read: bool, _socket_write: bool, _channel: bool) -> io::Result<()> {
+        thread::sleep(Duration::from_millis(1));
+        Ok(())
+    }
+}
+
+/// Turns the errors which only mean that the operation should be done
+/// again later into `None`.
+fn retry(res: io::Result<usize>) -> io::Result<Option<usize>> {
+    match res {
+        Ok(n) => Ok(Some(n)),
+        Err(ref e) if [ErrorKind::WouldBlock, ErrorKind::Interrupted].contains(&e.kind()) => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn socket_pair() -> io::Result<(Socket, Socket)> {
+    UnixStream::pair()
+}
+
+#[cfg(windows)]
+fn socket_pair() -> io::Result<(Socket, Socket)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        // Make sure it is our own connection that is accepted, and not one
+        // from another process which raced to the port
+        let (theirs, addr) = listener.accept()?;
+        if addr == ours.local_addr()? {
+            return Ok((ours, theirs));
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::io;
 use std::path::Path;
 
 use {raw, Error};
@@ -8,16 +10,20 @@ pub fn path2bytes(p: &Path) -> Result<Cow<[u8]>, Error> {
     use std::ffi::OsStr;
     use std::os::unix::prelude::*;
     let s: &OsStr = p.as_ref();
-    check(Cow::Borrowed(s.as_bytes()))
+    check(p, Cow::Borrowed(s.as_bytes()))
 }
 #[cfg(windows)]
 pub fn path2bytes(p: &Path) -> Result<Cow<[u8]>, Error> {
     p.to_str()
         .map(|s| s.as_bytes())
         .ok_or_else(|| {
-            Error::new(
+            Error::with_message(
                 raw::LIBSSH2_ERROR_INVAL,
-                "only unicode paths on windows may be used",
+                format!(
+                    "path contains invalid characters for SFTP transmission \
+                     (only unicode paths may be used on windows): {}",
+                    p.display()
+                ),
             )
         })
         .map(|bytes| {
@@ -34,16 +40,109 @@ pub fn path2bytes(p: &Path) -> Result<Cow<[u8]>, Error> {
                 Cow::Borrowed(bytes)
             }
         })
-        .and_then(check)
+        .and_then(|b| check(p, b))
 }
 
-fn check(b: Cow<[u8]>) -> Result<Cow<[u8]>, Error> {
+fn check<'a>(p: &Path, b: Cow<'a, [u8]>) -> Result<Cow<'a, [u8]>, Error> {
     if b.iter().any(|b| *b == 0) {
-        Err(Error::new(
+        Err(Error::with_message(
             raw::LIBSSH2_ERROR_INVAL,
-            "path provided contains a 0 byte",
+            format!("path provided contains a 0 byte: {:?}", p),
         ))
     } else {
         Ok(b)
     }
 }
+
+/// Error for a failure to access the local file at `path`.
+pub fn file_error(path: &Path, err: io::Error) -> Error {
+    Error::with_message(
+        raw::LIBSSH2_ERROR_FILE,
+        format!("{}: {}", path.display(), err),
+    )
+}
+
+/// The name of `path`, an entry that `Sftp::readdir` listed in `dir`.
+///
+/// Names come from the server, which could send one such as `x/..` or `/`
+/// to point outside of `dir`: anything but a single plain component is
+/// rejected with `LIBSSH2_ERROR_SFTP_PROTOCOL`.
+pub fn entry_name<'a>(dir: &Path, path: &'a Path) -> Result<&'a OsStr, Error> {
+    let invalid = || {
+        Error::with_message(
+            raw::LIBSSH2_ERROR_SFTP_PROTOCOL,
+            format!("invalid name in {}: {}", dir.display(), path.display()),
+        )
+    };
+    // Checked on the bytes, as `Path` skips `.` and trailing slashes, as in
+    // `x/.`, when splitting into components
+    let (path_bytes, dir_bytes) = (path2bytes(path)?, path2bytes(dir)?);
+    if !path_bytes.starts_with(&dir_bytes) {
+        return Err(invalid());
+    }
+    let mut name = &path_bytes[dir_bytes.len()..];
+    if !dir_bytes.is_empty() && !dir_bytes.ends_with(b"/") {
+        if !name.starts_with(b"/") {
+            return Err(invalid());
+        }
+        name = &name[1..];
+    }
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(invalid());
+    }
+    path.file_name().ok_or_else(invalid)
+}
+
+/// An `io::Error` of kind `Other` carrying `msg`.
+// `io::Error::other` is too recent for the versions of Rust supported here
+#[allow(unknown_lints, clippy::io_other_error)]
+pub fn io_other(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+/// Decodes standard base64, skipping whitespace.
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_name_rejects_escapes() {
+        let dir = Path::new("/srv/data");
+        let name = |name: &str| {
+            entry_name(dir, &dir.join(name))
+                .map(OsStr::to_os_string)
+                .ok()
+        };
+        assert_eq!(name("foo").unwrap(), "foo");
+        assert_eq!(name(".hidden").unwrap(), ".hidden");
+        for bad in &["x/..", "/", "/etc", "..", ".", "a/b", "x/.", "x/", ""] {
+            assert_eq!(name(bad), None, "{}", bad);
+        }
+    }
+}
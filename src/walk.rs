@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use sftp::{FileStat, Sftp};
+use {raw, util, Error};
+
+/// An iterator over the tree under a remote directory.
+///
+/// This is created by `Sftp::walk`. Each file and directory below the root
+/// is yielded with its metadata, depth first, the entries of a directory in
+/// the order of their names, and right after the directory itself. The
+/// root itself is not yielded. A directory which can't be listed yields an
+/// error after its entry, and the walk goes on with the rest of the tree.
+///
+/// Symlinks are yielded as such and not followed, unless `follow_symlinks`
+/// is set.
+pub struct Walk {
+    sftp: Sftp,
+    root: PathBuf,
+    follow_symlinks: bool,
+    started: bool,
+    // Entries found but not yielded yet, the next one last, with the real
+    // path of the directories when following symlinks
+    stack: Vec<(PathBuf, Option<PathBuf>, FileStat)>,
+    // Real paths of the directories entered, when following symlinks
+    visited: HashSet<PathBuf>,
+    // Failure to list the directory yielded last
+    error: Option<Error>,
+}
+
+impl Walk {
+    pub(crate) fn new(sftp: Sftp, root: &Path) -> Walk {
+        Walk {
+            sftp,
+            root: root.to_path_buf(),
+            follow_symlinks: false,
+            started: false,
+            stack: Vec::new(),
+            visited: HashSet::new(),
+            error: None,
+        }
+    }
+
+    /// Whether to follow symlinks, yielding them with the metadata of what
+    /// they point to and walking into those which point to directories.
+    ///
+    /// Following links could make the walk go round in circles, so the real
+    /// path of every directory entered is recorded, as resolved by the
+    /// server with `realpath`. A link to a directory which was already
+    /// entered, whether it leads back to one of its parents or elsewhere in
+    /// the tree, yields an error with `LIBSSH2_ERROR_INVAL` in place of its
+    /// entry and is not walked again. So does a link which can't be
+    /// resolved, as when its target is missing. A directory which was
+    /// already entered through a link is yielded, but not walked again.
+    pub fn follow_symlinks(mut self, follow: bool) -> Walk {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Queues up the entries of `dir`, whose real path is `real` when
+    /// following symlinks.
+    ///
+    /// Entries with a name which isn't a plain file name, as a broken server
+    /// could send, are left out, and the first of them reported once the
+    /// others are queued.
+    fn list(&mut self, dir: &Path, real: Option<PathBuf>) -> Result<(), Error> {
+        let mut entries = self.sftp.readdir(dir)?;
+        entries.sort_by(|a, b| b.0.cmp(&a.0));
+        let mut invalid = None;
+        for (path, stat) in entries {
+            let name = match util::entry_name(dir, &path) {
+                Ok(name) => name.to_os_string(),
+                Err(err) => {
+                    invalid = invalid.or(Some(err));
+                    continue;
+                }
+            };
+            let real = real.as_ref().map(|real| real.join(name));
+            self.stack.push((path, real, stat));
+        }
+        match invalid {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        let root = self.root.clone();
+        if self.follow_symlinks {
+            let real = self.sftp.realpath(&root)?;
+            let _ = self.visited.insert(real.clone());
+            self.list(&root, Some(real))
+        } else {
+            self.list(&root, None)
+        }
+    }
+
+    /// Resolves the symlink at `path`, returning the metadata of its target
+    /// and the real path of the target if it is a directory to walk.
+    fn follow(&mut self, path: &Path) -> Result<(FileStat, Option<PathBuf>), Error> {
+        let stat = self.sftp.stat(path)?;
+        if !stat.is_dir() {
+            return Ok((stat, None));
+        }
+        let real = self.sftp.realpath(path)?;
+        if !self.visited.insert(real.clone()) {
+            return Err(Error::with_message(
+                raw::LIBSSH2_ERROR_INVAL,
+                format!(
+                    "{}: symlink loop, {} was already walked",
+                    path.display(),
+                    real.display()
+                ),
+            ));
+        }
+        Ok((stat, Some(real)))
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<(PathBuf, FileStat), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        if !self.started {
+            self.started = true;
+            if let Err(err) = self.start() {
+                return Some(Err(err));
+            }
+        }
+
+        let (path, mut real, mut stat) = self.stack.pop()?;
+        if self.follow_symlinks && stat.file_type().is_symlink() {
+            match self.follow(&path) {
+                Ok((target, target_real)) => {
+                    stat = target;
+                    real = target_real;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        } else if let Some(ref dir) = real {
+            // A directory reached without following a link can still be the
+            // target of one which was followed first
+            if stat.is_dir() && !self.visited.insert(dir.clone()) {
+                return Some(Ok((path, stat)));
+            }
+        }
+        if stat.is_dir() {
+            if let Err(err) = self.list(&path, real) {
+                self.error = Some(err);
+            }
+        }
+        Some(Ok((path, stat)))
+    }
+}
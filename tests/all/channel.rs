@@ -1,7 +1,10 @@
 use ssh2::Channel;
+use std::io;
 use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
+use std::process::Command;
 use std::thread;
+use std::time::{Duration, Instant};
 
 /// Consume all available stdout and stderr data.
 /// It is important to read both if you are using
@@ -87,6 +90,72 @@ fn reading_data() {
     assert_eq!(output, "foo\n");
 }
 
+#[test]
+fn shell_quote() {
+    assert_eq!(ssh2::shell_quote("foo/bar.txt"), "foo/bar.txt");
+    assert_eq!(ssh2::shell_quote(""), "''");
+    assert_eq!(ssh2::shell_quote("=ls"), "'=ls'");
+    assert_eq!(ssh2::shell_join(&["FOO=bar", "cmd"]), "'FOO=bar' cmd");
+    assert_eq!(
+        ssh2::shell_join(&["echo", "a b", "'"]),
+        r#"echo 'a b' ''\'''"#
+    );
+
+    // The local shell must read the arguments back unchanged
+    let args = [
+        "FOO=bar",
+        "$(id)",
+        "a'b",
+        "\\n `x` ; | & * ?",
+        "\n",
+        "é",
+        "=ls",
+    ];
+    let script = format!("printf '%s\\0' {}", ssh2::shell_join(&args));
+    let out = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+    let mut expected = args.join("\0");
+    expected.push('\0');
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), expected);
+}
+
+#[test]
+fn lines() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel
+        .exec("printf 'foo\\nbar\\r\\n'; sleep 1; printf baz")
+        .unwrap();
+    let lines = channel.lines().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(lines, ["foo", "bar", "baz"]);
+}
+
+#[test]
+fn wait_readable() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("sleep 1; echo foo").unwrap();
+    assert!(!channel
+        .wait_readable(Some(Duration::from_millis(100)))
+        .unwrap());
+    assert!(channel.wait_readable(None).unwrap());
+    let mut output = String::new();
+    channel.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "foo\n");
+    assert!(channel
+        .wait_readable(Some(Duration::from_millis(0)))
+        .unwrap());
+}
+
+#[test]
+fn set_window_sizes() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    let window = 16 * 1024 * 1024;
+    channel.set_window_sizes(window, 0).unwrap();
+    assert!(channel.read_window().remaining >= window);
+    assert!(channel.set_window_sizes(0, u32::max_value()).is_err());
+}
+
 #[test]
 fn handle_extended_data() {
     let sess = ::authed_session();
@@ -190,6 +259,41 @@ fn forward() {
     t.join().ok().unwrap();
 }
 
+#[test]
+fn forward_accept_timeout() {
+    let sess = ::authed_session();
+    let (mut listen, port) = sess.channel_forward_listen(0, None, None).unwrap();
+    let err = listen
+        .accept_timeout(Duration::from_millis(100))
+        .err()
+        .unwrap();
+    assert_eq!(err.code(), -9); // LIBSSH2_ERROR_TIMEOUT
+    assert!(sess.is_blocking());
+
+    let t = thread::spawn(move || {
+        let mut s = TcpStream::connect(&("127.0.0.1", port)).unwrap();
+        s.write_all(&[1, 2, 3]).unwrap();
+    });
+    let mut channel = listen.accept_timeout(Duration::from_secs(10)).unwrap();
+    let mut r = [0, 0, 0];
+    channel.read_exact(&mut r).unwrap();
+    assert_eq!(r, [1, 2, 3]);
+    t.join().ok().unwrap();
+}
+
+#[test]
+fn forward_cancel() {
+    let sess = ::authed_session();
+    let (listen, port) = sess.channel_forward_listen(0, None, None).unwrap();
+    listen.cancel().unwrap();
+    // The server isn't asked to confirm, so give it some time to stop
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(&("127.0.0.1", port)).is_ok() {
+        assert!(Instant::now() < deadline, "port {} still forwarded", port);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
 #[test]
 fn drop_nonblocking() {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
@@ -264,3 +368,28 @@ fn pty_modes_are_propagated() {
     // This may well be linux specific
     assert!(out.contains("intr = y"), "mode was propagated");
 }
+
+#[test]
+fn exec_capture() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    let output = channel
+        .exec_capture("echo foo; echo bar >&2; exit 3", None)
+        .unwrap();
+    assert_eq!(output.stdout, b"foo\n");
+    assert_eq!(output.stderr, b"bar\n");
+    assert_eq!(output.exit_status, 3);
+    assert!(sess.is_blocking());
+}
+
+#[test]
+fn exec_capture_deadline() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    let deadline = Instant::now() + Duration::from_millis(200);
+    let err = channel
+        .exec_capture("sleep 10", Some(deadline))
+        .unwrap_err();
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::TimedOut);
+    assert!(Instant::now() < deadline + Duration::from_secs(5));
+}
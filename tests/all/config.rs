@@ -0,0 +1,63 @@
+use ssh2::SshConfig;
+use std::path::Path;
+
+const CONFIG: &str = "
+# Global settings come first
+Port 2222
+
+Host bastion
+    HostName bastion.example.com
+    User = admin
+
+Host *.internal !secret.internal
+    ProxyJump bastion
+    IdentityFile \"/keys/%h key\"
+
+Host *
+    User nobody
+    IdentityFile /keys/default
+    Port 22
+";
+
+#[test]
+fn host() {
+    let config = SshConfig::parse(CONFIG).unwrap();
+
+    let bastion = config.host("bastion");
+    assert_eq!(bastion.host_name(), "bastion.example.com");
+    assert_eq!(bastion.port(), 2222);
+    assert_eq!(bastion.user(), Some("admin"));
+    assert_eq!(bastion.identity_files(), [Path::new("/keys/default")]);
+    assert!(bastion.proxy_jump().is_empty());
+
+    let db = config.host("db.internal");
+    assert_eq!(db.host_name(), "db.internal");
+    assert_eq!(db.user(), Some("nobody"));
+    assert_eq!(db.proxy_jump(), ["bastion"]);
+    assert_eq!(
+        db.identity_files(),
+        [
+            Path::new("/keys/db.internal key"),
+            Path::new("/keys/default")
+        ]
+    );
+
+    assert!(config.host("secret.internal").proxy_jump().is_empty());
+}
+
+#[test]
+fn syntax_errors() {
+    assert!(SshConfig::parse("Port none").is_err());
+    assert!(SshConfig::parse("IdentityFile \"unterminated").is_err());
+    assert!(SshConfig::parse("User").is_err());
+}
+
+#[test]
+fn expands_home() {
+    let home = std::env::var("HOME").unwrap();
+    let config = SshConfig::parse("IdentityFile ~/.ssh/id_%p").unwrap();
+    assert_eq!(
+        config.host("any").identity_files(),
+        [Path::new(&home).join(".ssh/id_22")]
+    );
+}
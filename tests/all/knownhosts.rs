@@ -1,4 +1,6 @@
-use ssh2::{KnownHostFileKind, Session};
+use ssh2::{CheckResult, KnownHostFileKind, KnownHostKeyFormat, Session};
+use std::fs;
+use tempdir::TempDir;
 
 #[test]
 fn smoke() {
@@ -30,6 +32,9 @@ PW3RcT0eOzQgqlJL3RKrTJvdsjE3JEAvGq3lGHSZXy28G3skua2SmVi\
     assert_eq!(hosts.len(), 1);
     let host = &hosts[0];
     assert_eq!(host.name(), None);
+    assert!(host.is_hashed());
+    assert_eq!(host.key_format(), KnownHostKeyFormat::SshRsa);
+    assert_eq!(host.key_bytes().unwrap()[..11], b"\0\0\0\x07ssh-rsa"[..]);
     assert_eq!(
         host.key(),
         "\
@@ -50,3 +55,129 @@ PW3RcT0eOzQgqlJL3RKrTJvdsjE3JEAvGq3lGHSZXy28G3skua2SmVi\
     );
     known_hosts.remove(host).unwrap();
 }
+
+#[test]
+fn check_host() {
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .read_str(
+            "example.com ssh-ed25519 \
+             AAAAC3NzaC1lZDI1NTE5AAAAIGNg2N5aeGm9rc9jCpCWyWZz4wg+3tf2Sek4wj20xSFp",
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    let key = known_hosts.hosts().unwrap()[0].key_bytes().unwrap();
+    assert_eq!(
+        known_hosts.check_host("example.com", 22, &key),
+        CheckResult::Match
+    );
+    assert_eq!(
+        known_hosts.check_host("example.org", 22, &key),
+        CheckResult::NotFound
+    );
+
+    let mut changed = key.clone();
+    *changed.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        known_hosts.check_host("example.com", 22, &changed),
+        CheckResult::Mismatch
+    );
+
+    let mut rsa = b"\0\0\0\x07ssh-rsa".to_vec();
+    rsa.extend_from_slice(&[0, 0, 0, 1, 3, 0, 0, 0, 1, 1]);
+    assert_eq!(
+        known_hosts.check_host("example.com", 22, &rsa),
+        CheckResult::NotFound
+    );
+    assert_eq!(
+        known_hosts.check_port("example.com", 22, &rsa),
+        CheckResult::Mismatch
+    );
+}
+
+#[test]
+fn add_and_write() {
+    let td = TempDir::new("known_hosts").unwrap();
+    let file = td.path().join("known_hosts");
+    fs::write(
+        &file,
+        "example.com ssh-ed25519 \
+         AAAAC3NzaC1lZDI1NTE5AAAAIGNg2N5aeGm9rc9jCpCWyWZz4wg+3tf2Sek4wj20xSFp\n",
+    )
+    .unwrap();
+
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .read_file(&file, KnownHostFileKind::OpenSSH)
+        .unwrap();
+    let key = known_hosts.hosts().unwrap()[0].key_bytes().unwrap();
+    known_hosts
+        .add_and_write(&file, "example.org", &key, KnownHostKeyFormat::Ed255219, "")
+        .unwrap();
+
+    let mut reread = sess.known_hosts().unwrap();
+    assert_eq!(
+        reread.read_file(&file, KnownHostFileKind::OpenSSH).unwrap(),
+        2
+    );
+    assert_eq!(
+        reread.check_host("example.org", 22, &key),
+        CheckResult::Match
+    );
+    assert_eq!(fs::read_dir(td.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn remove_host() {
+    let key = "ssh-ed25519 \
+               AAAAC3NzaC1lZDI1NTE5AAAAIGNg2N5aeGm9rc9jCpCWyWZz4wg+3tf2Sek4wj20xSFp";
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    for host in &[
+        "example.com",
+        "|1|MDEyMzQ1Njc4OWFiY2RlZmdoaWo=|jaHXoMQTU/+rEgquOJTQzPGCF4I=",
+        "[example.com]:2222",
+        "example.org",
+    ] {
+        known_hosts
+            .read_str(&format!("{} {}", host, key), KnownHostFileKind::OpenSSH)
+            .unwrap();
+    }
+
+    assert_eq!(known_hosts.remove_host("example.com", None).unwrap(), 2);
+    assert_eq!(known_hosts.hosts().unwrap().len(), 2);
+    assert_eq!(
+        known_hosts.remove_host("example.com", Some(2222)).unwrap(),
+        1
+    );
+    assert_eq!(known_hosts.remove_host("example.com", None).unwrap(), 0);
+    let hosts = known_hosts.hosts().unwrap();
+    assert_eq!(hosts.len(), 1);
+    assert_eq!(hosts[0].name(), Some("example.org"));
+}
+
+#[test]
+fn read_str_merges() {
+    let td = TempDir::new("known_hosts").unwrap();
+    let file = td.path().join("known_hosts");
+    let line = "example.com ssh-ed25519 \
+                AAAAC3NzaC1lZDI1NTE5AAAAIGNg2N5aeGm9rc9jCpCWyWZz4wg+3tf2Sek4wj20xSFp";
+    fs::write(&file, format!("{}\n", line)).unwrap();
+
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .read_file(&file, KnownHostFileKind::OpenSSH)
+        .unwrap();
+    known_hosts
+        .read_str(&line.replace(".com", ".org"), KnownHostFileKind::OpenSSH)
+        .unwrap();
+    known_hosts
+        .read_str(line, KnownHostFileKind::OpenSSH)
+        .unwrap();
+    let hosts = known_hosts.hosts().unwrap();
+    let names: Vec<_> = hosts.iter().map(|h| h.name().unwrap()).collect();
+    assert_eq!(names, ["example.com", "example.org", "example.com"]);
+}
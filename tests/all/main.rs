@@ -1,6 +1,7 @@
 #![deny(warnings)]
 
 extern crate ssh2;
+extern crate tar;
 extern crate tempdir;
 
 use std::env;
@@ -8,6 +9,8 @@ use std::net::TcpStream;
 
 mod agent;
 mod channel;
+#[cfg(feature = "ssh-config")]
+mod config;
 mod knownhosts;
 mod session;
 mod sftp;
@@ -2,9 +2,14 @@ use std::env;
 use std::fs::File;
 use std::io::{self, prelude::*};
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempdir::TempDir;
 
-use ssh2::{BlockDirections, HashType, KeyboardInteractivePrompt, MethodType, Prompt, Session};
+use ssh2::{
+    BlockDirections, HashType, HostKeyType, KeyboardInteractivePrompt, MethodType,
+    NegotiatedAlgorithms, Prompt, Session, SessionConfig, TraceFlags,
+};
 
 #[test]
 fn session_is_send() {
@@ -16,6 +21,16 @@ fn session_is_send() {
     assert!(must_be_send(&sess));
 }
 
+#[test]
+fn trace() {
+    let sess = Session::new().unwrap();
+    sess.set_trace(TraceFlags::KEX | TraceFlags::AUTH);
+    sess.set_trace_handler(|msg| assert!(!msg.ends_with('\n')))
+        .unwrap();
+    sess.set_trace_handler(|_| {}).unwrap();
+    sess.set_trace(TraceFlags::empty());
+}
+
 #[test]
 fn smoke() {
     let sess = Session::new().unwrap();
@@ -23,6 +38,7 @@ fn smoke() {
     sess.set_banner("foo").unwrap();
     assert!(sess.is_blocking());
     assert_eq!(sess.timeout(), 0);
+    assert!(sess.active_methods().is_none());
     sess.set_compress(true);
     assert!(sess.host_key().is_none());
     sess.method_pref(MethodType::Kex, "diffie-hellman-group14-sha1")
@@ -35,6 +51,69 @@ fn smoke() {
     sess.channel_session().err().unwrap();
 }
 
+#[test]
+fn config() {
+    let config = SessionConfig::new()
+        .banner("SSH-2.0-foo")
+        .compress(true)
+        .method_pref(MethodType::Kex, "diffie-hellman-group14-sha1")
+        .timeout(1000)
+        .keepalive(false, 10)
+        .blocking(false);
+    let sess = config.build().unwrap();
+    assert_eq!(sess.timeout(), 1000);
+    assert!(sess.is_blocking());
+}
+
+#[test]
+fn config_connect() {
+    let sess = SessionConfig::new()
+        .method_pref(MethodType::Kex, "diffie-hellman-group14-sha256")
+        .timeout(10_000)
+        .blocking(false)
+        .connect(::socket())
+        .unwrap();
+    assert!(!sess.is_blocking());
+    assert_eq!(sess.timeout(), 10_000);
+    assert_eq!(
+        sess.methods(MethodType::Kex),
+        Some("diffie-hellman-group14-sha256")
+    );
+}
+
+#[test]
+fn weak_algorithms() {
+    let strong = NegotiatedAlgorithms {
+        kex: "curve25519-sha256".to_string(),
+        host_key: "rsa-sha2-512".to_string(),
+        crypt_cs: "aes256-ctr".to_string(),
+        crypt_sc: "chacha20-poly1305@openssh.com".to_string(),
+        mac_cs: "hmac-sha2-256".to_string(),
+        mac_sc: "hmac-sha1".to_string(),
+        comp_cs: "none".to_string(),
+        comp_sc: "none".to_string(),
+    };
+    assert!(strong.weak_algorithms().is_empty());
+
+    let weak = NegotiatedAlgorithms {
+        kex: "diffie-hellman-group14-sha1".to_string(),
+        host_key: "ssh-rsa".to_string(),
+        crypt_cs: "aes128-cbc".to_string(),
+        mac_sc: "hmac-md5".to_string(),
+        ..strong.clone()
+    };
+    assert_eq!(
+        weak.weak_algorithms(),
+        vec![
+            "diffie-hellman-group14-sha1",
+            "ssh-rsa",
+            "aes128-cbc",
+            "hmac-md5"
+        ]
+    );
+    assert!(!Session::new().unwrap().is_using_weak_crypto());
+}
+
 #[test]
 fn smoke_handshake() {
     let user = env::var("USER").unwrap();
@@ -42,7 +121,15 @@ fn smoke_handshake() {
     let mut sess = Session::new().unwrap();
     sess.set_tcp_stream(socket);
     sess.handshake().unwrap();
-    sess.host_key().unwrap();
+    let algorithms = sess.active_methods().unwrap();
+    assert_eq!(algorithms.comp_cs, "none");
+    assert_eq!(
+        Some(&algorithms.crypt_sc[..]),
+        sess.methods(MethodType::CryptSc)
+    );
+    let (_, key_type) = sess.host_key().unwrap();
+    assert_ne!(key_type, HostKeyType::Unknown);
+    assert_ne!(key_type.to_string(), "unknown");
     let methods = sess.auth_methods(&user).unwrap();
     assert!(methods.contains("publickey"), "{}", methods);
     assert!(!sess.authenticated());
@@ -142,6 +229,26 @@ fn keepalive() {
     sess.keepalive_send().unwrap();
 }
 
+#[test]
+fn wait_any() {
+    let sess = ::authed_session();
+    let mut slow = sess.channel_session().unwrap();
+    slow.exec("sleep 10").unwrap();
+    let mut fast = sess.channel_session().unwrap();
+    fast.exec("sleep 1; echo foo").unwrap();
+    let channels = [&slow, &fast];
+    let timeout = Some(Duration::from_millis(100));
+    assert_eq!(sess.wait_any(&channels, timeout).unwrap(), None);
+    assert_eq!(sess.wait_any(&channels, None).unwrap(), Some(1));
+}
+
+#[test]
+fn ping() {
+    let sess = ::authed_session();
+    sess.ping().unwrap();
+    sess.ping().unwrap();
+}
+
 #[test]
 fn scp_recv() {
     let sess = ::authed_session();
@@ -186,3 +293,72 @@ fn block_directions() {
     assert_eq!(actual, Err(io::ErrorKind::WouldBlock));
     assert_eq!(sess.block_directions(), BlockDirections::Inbound);
 }
+
+#[test]
+fn poll_until() {
+    let sess = ::authed_session();
+    sess.set_blocking(false);
+    let mut channel = sess
+        .poll_until(
+            || sess.channel_session(),
+            |dirs| {
+                assert_ne!(dirs, BlockDirections::None);
+                thread::sleep(Duration::from_millis(10));
+            },
+        )
+        .unwrap();
+    sess.set_blocking(true);
+    channel.exec("true").unwrap();
+    channel.wait_close().unwrap();
+}
+
+#[test]
+fn connect_via() {
+    let user = env::var("USER").unwrap();
+    let jump = ::authed_session();
+    let port = ::test_addr().rsplit(':').next().unwrap().parse().unwrap();
+    let sess = Session::connect_via(&jump, "127.0.0.1", port).unwrap();
+    // The relay doesn't take the mode of the jump session over
+    assert!(jump.is_blocking());
+    sess.userauth_agent(&user).unwrap();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("echo foo").unwrap();
+    let mut output = String::new();
+    channel.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "foo\n");
+    channel.wait_close().unwrap();
+}
+
+#[test]
+fn reconnect() {
+    let user = env::var("USER").unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_compress(true);
+    sess.set_timeout(10_000);
+    sess.set_tcp_stream(::socket());
+    sess.handshake().unwrap();
+    sess.enable_reconnect();
+    sess.userauth_agent(&user).unwrap();
+    let old = sess.clone();
+
+    sess.reconnect(::socket()).unwrap();
+    assert!(sess.authenticated());
+    assert_eq!(sess.timeout(), 10_000);
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("true").unwrap();
+
+    // Clones made before stay on the previous connection
+    assert!(old.authenticated());
+    assert_eq!(old.timeout(), 10_000);
+}
+
+#[test]
+fn reconnect_without_credentials() {
+    let user = env::var("USER").unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(::socket());
+    sess.handshake().unwrap();
+    sess.userauth_agent(&user).unwrap();
+    sess.reconnect(::socket()).unwrap();
+    assert!(!sess.authenticated());
+}
@@ -1,5 +1,8 @@
+use ssh2::{OpenFlags, OpenType};
 use std::fs::{self, File};
 use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::Path;
 use tempdir::TempDir;
 
 #[test]
@@ -49,4 +52,1101 @@ fn ops() {
 
     let files = sftp.readdir(td.path()).unwrap();
     assert_eq!(files.len(), 4);
+
+    let mut dir = sftp.opendir(td.path()).unwrap();
+    let mut names = Vec::new();
+    while let Ok(name) = dir.readdir_names() {
+        names.push(name);
+    }
+    // `.` and `..` are not filtered out here
+    assert_eq!(names.len(), 6);
+}
+
+#[test]
+fn readdir_with_longname() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut dir = sftp.opendir(td.path()).unwrap();
+    loop {
+        let (name, longname, stat) = dir.readdir_with_longname().unwrap();
+        if name == Path::new("foo") {
+            assert!(longname.ends_with("foo"), "{}", longname);
+            assert_eq!(stat.size, Some(0));
+            break;
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn create_with_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    for &mode in &[0o755, 0o600] {
+        sftp.create_with_mode(&td.path().join("foo"), mode)
+            .unwrap()
+            .write_all(b"foo")
+            .unwrap();
+        let meta = fs::metadata(&td.path().join("foo")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, mode as u32);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn set_default_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let mut sftp = sess.sftp().unwrap();
+    let other = sftp.clone();
+    sftp.set_default_mode(0o600);
+    sftp.create(&td.path().join("foo")).unwrap();
+    sftp.clone().create(&td.path().join("bar")).unwrap();
+    other.create(&td.path().join("baz")).unwrap();
+    let mode = |name| {
+        fs::metadata(td.path().join(name))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+    assert_eq!(mode("foo"), 0o600);
+    assert_eq!(mode("bar"), 0o600);
+    assert_ne!(mode("baz"), 0o600);
+}
+
+#[test]
+fn open_and_verify() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    fs::write(&path, b"foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let stat = sftp.stat(&path).unwrap();
+
+    let mut contents = String::new();
+    let mut file = sftp.open_and_verify(&path, &stat).unwrap();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "foo");
+    assert!(sftp
+        .open_and_verify(&path, &ssh2::FileStat::empty())
+        .is_ok());
+
+    fs::write(&path, b"foobar").unwrap();
+    let err = sftp.open_and_verify(&path, &stat).err().unwrap();
+    assert_eq!(err.code(), -16); // LIBSSH2_ERROR_FILE
+    let moved = stat
+        .clone()
+        .with_size(6)
+        .with_mtime(stat.mtime.unwrap() - 10);
+    assert!(sftp.open_and_verify(&path, &moved).is_err());
+}
+
+#[test]
+fn read_range() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    let data = (0..1000u32).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write(&path, &data).unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+
+    assert_eq!(sftp.read_range(&path, 10, 20).unwrap(), &data[10..30]);
+    assert_eq!(sftp.read_range(&path, 0, 0).unwrap(), b"");
+    assert_eq!(sftp.read_range(&path, 990, 100).unwrap(), &data[990..]);
+    assert_eq!(sftp.read_range(&path, 0, usize::max_value()).unwrap(), data);
+    assert_eq!(sftp.read_range(&path, 2000, 10).unwrap(), b"");
+    assert!(sftp.read_range(&td.path().join("bar"), 0, 10).is_err());
+}
+
+#[test]
+fn cwd() {
+    let td = TempDir::new("foo").unwrap();
+    fs::create_dir(td.path().join("sub")).unwrap();
+    fs::write(td.path().join("sub/foo"), b"foo").unwrap();
+    let sess = ::authed_session();
+    let mut sftp = sess.sftp().unwrap();
+    assert!(sftp.cwd().is_none());
+    assert_eq!(sftp.cwd_join(Path::new("foo")), Path::new("foo"));
+
+    sftp.set_cwd(td.path());
+    sftp.set_cwd(Path::new("sub"));
+    assert_eq!(sftp.cwd(), Some(&*td.path().join("sub")));
+    assert_eq!(sftp.stat_rel(Path::new("foo")).unwrap().size, Some(3));
+    let mut contents = String::new();
+    sftp.open_rel(Path::new("foo"))
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "foo");
+    let names = sftp
+        .readdir_rel(Path::new("."))
+        .unwrap()
+        .into_iter()
+        .map(|(path, _)| path.file_name().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["foo"]);
+    assert!(sftp.stat_rel(&td.path().join("sub")).unwrap().is_dir());
+    assert_eq!(sftp.clone().cwd(), sftp.cwd());
+}
+
+#[test]
+fn open_missing_reports_server_status() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let err = sftp.open(&td.path().join("missing")).err().unwrap();
+    assert_eq!(err.message(), "no such file");
+}
+
+#[test]
+fn pool() {
+    let sessions = vec![::authed_session(), ::authed_session()];
+    let sftps = sessions.iter().map(|s| s.sftp().unwrap()).collect();
+    let pool = ssh2::SftpPool::new(sftps);
+    assert_eq!(pool.channels(), 2);
+    for _ in 0..4 {
+        pool.get().stat(Path::new("/")).unwrap();
+    }
+}
+
+#[test]
+fn open_in() {
+    let td = TempDir::new("foo").unwrap();
+    fs::create_dir(&td.path().join("bar")).unwrap();
+    File::create(&td.path().join("bar/baz"))
+        .unwrap()
+        .write_all(b"baz")
+        .unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let root = sftp.opendir(td.path()).unwrap();
+    let bar = sftp
+        .open_in(&root, Path::new("bar"), OpenFlags::READ, 0, OpenType::Dir)
+        .unwrap();
+    let mut s = String::new();
+    sftp.open_in(&bar, Path::new("baz"), OpenFlags::READ, 0, OpenType::File)
+        .unwrap()
+        .read_to_string(&mut s)
+        .unwrap();
+    assert_eq!(s, "baz");
+}
+
+#[test]
+fn stream_position() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo"))
+        .unwrap()
+        .write_all(b"foobar")
+        .unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.open(&td.path().join("foo")).unwrap();
+    assert_eq!(file.stream_position().unwrap(), 0);
+    let mut buf = [0; 3];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(file.stream_position().unwrap(), 3);
+}
+
+#[test]
+fn unlink_many() {
+    let td = TempDir::new("foo").unwrap();
+    let paths = vec![
+        td.path().join("a"),
+        td.path().join("b"),
+        td.path().join("c"),
+    ];
+    File::create(&paths[0]).unwrap();
+    File::create(&paths[2]).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let results = sftp.unlink_many(&paths);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+    assert!(results[2].is_ok());
+    assert!(!paths[0].exists() && !paths[2].exists());
+}
+
+#[test]
+fn read_ahead() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo"))
+        .unwrap()
+        .write_all(b"foobarbaz")
+        .unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.open(&td.path().join("foo")).unwrap();
+    file.set_read_ahead(1024);
+    let mut buf = [0; 3];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"foo");
+    assert_eq!(file.stream_position().unwrap(), 3);
+    file.seek(SeekFrom::Current(3)).unwrap();
+    let mut rest = String::new();
+    file.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "baz");
+}
+
+#[test]
+fn filestat_builder() {
+    let stat = ssh2::FileStat::empty().with_perm(0o600).with_mtime(42);
+    assert_eq!(stat.perm, Some(0o600));
+    assert_eq!(stat.mtime, Some(42));
+    assert_eq!(stat.size, None);
+    assert_eq!(stat.atime, None);
+}
+
+#[test]
+fn present_flags() {
+    let stat = ssh2::FileStat::empty().with_perm(0o600).with_mtime(42);
+    assert_eq!(stat.present_flags(), 0x4 | 0x8); // LIBSSH2_SFTP_ATTR_PERMISSIONS | ACMODTIME
+    assert_eq!(ssh2::FileStat::empty().present_flags(), 0);
+}
+
+#[test]
+fn filestat_needs_update() {
+    let local = ssh2::FileStat::empty().with_size(3).with_mtime(100);
+    assert!(!local.needs_update(&local.clone()));
+    assert!(local.needs_update(&local.clone().with_size(4)));
+    assert!(local.needs_update(&local.clone().with_mtime(101)));
+    assert!(!local.needs_update_within(&local.clone().with_mtime(101), 2));
+    assert!(local.needs_update_within(&local.clone().with_mtime(98), 2));
+    assert!(local.needs_update(&ssh2::FileStat::empty().with_size(3)));
+}
+
+#[test]
+fn clone_shares_channel() {
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let other = sftp.clone();
+    drop(sftp);
+    assert!(other.stat(Path::new("/")).unwrap().is_dir());
+}
+
+#[test]
+fn statvfs() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let stat = sftp.statvfs(td.path()).unwrap();
+    assert!(stat.free_inodes() <= stat.total_inodes());
+    assert!(stat.raw().f_bsize > 0);
+    assert!(sftp.has_free_inodes(td.path(), 1).unwrap());
+    if stat.total_inodes() != 0 {
+        assert!(!sftp.has_free_inodes(td.path(), u64::max_value()).unwrap());
+    }
+    assert!(sftp.statvfs(&td.path().join("missing")).is_err());
+}
+
+#[test]
+fn metadata() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo"))
+        .unwrap()
+        .write_all(b"foo")
+        .unwrap();
+    let local = fs::metadata(&td.path().join("foo")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let meta = sftp.metadata(&td.path().join("foo")).unwrap();
+    assert!(meta.is_file());
+    assert!(!meta.is_symlink());
+    assert_eq!(meta.len(), 3);
+    let secs =
+        |t: std::time::SystemTime| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(
+        secs(meta.modified().unwrap()),
+        secs(local.modified().unwrap())
+    );
+    assert!(sftp.metadata(td.path()).unwrap().is_dir());
+}
+
+#[cfg(unix)]
+#[test]
+fn metadata_opt() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo")).unwrap();
+    std::os::unix::fs::symlink("foo", td.path().join("link")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let link = td.path().join("link");
+    assert!(sftp.metadata_opt(&link, true).unwrap().is_file());
+    assert!(sftp
+        .metadata_opt(&link, false)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+#[cfg(unix)]
+#[test]
+fn stat_with_target() {
+    let td = TempDir::new("foo").unwrap();
+    fs::write(td.path().join("foo"), b"foo").unwrap();
+    std::os::unix::fs::symlink("foo", td.path().join("link")).unwrap();
+    std::os::unix::fs::symlink("missing", td.path().join("dangling")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let (target, stat) = sftp.stat_with_target(&td.path().join("link")).unwrap();
+    assert_eq!(target.unwrap(), Path::new("foo"));
+    assert!(stat.is_file());
+    assert_eq!(stat.size, Some(3));
+
+    let (target, stat) = sftp.stat_with_target(&td.path().join("foo")).unwrap();
+    assert!(target.is_none());
+    assert_eq!(stat.size, Some(3));
+
+    assert!(sftp.stat_with_target(&td.path().join("dangling")).is_err());
+    assert!(sftp.stat_with_target(&td.path().join("nope")).is_err());
+}
+
+#[test]
+fn exclusive_create_existing() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let err = sftp
+        .open_mode(
+            &td.path().join("foo"),
+            OpenFlags::WRITE | OpenFlags::EXCLUSIVE,
+            0o644,
+            OpenType::File,
+        )
+        .err()
+        .unwrap();
+    assert_eq!(err.message(), "file already exists");
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::AlreadyExists);
+    sftp.open_mode(
+        &td.path().join("bar"),
+        OpenFlags::WRITE | OpenFlags::EXCLUSIVE,
+        0o644,
+        OpenType::File,
+    )
+    .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn upload_path() {
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+    let td = TempDir::new("foo").unwrap();
+    let local = td.path().join("local");
+    File::create(&local).unwrap().write_all(b"foo").unwrap();
+    fs::set_permissions(&local, fs::Permissions::from_mode(0o640)).unwrap();
+    symlink("local", td.path().join("link")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let remote = td.path().join("remote");
+    sftp.upload_path(&local, &remote).unwrap();
+    let (local_meta, remote_meta) = (
+        fs::metadata(&local).unwrap(),
+        fs::metadata(&remote).unwrap(),
+    );
+    assert_eq!(fs::read(&remote).unwrap(), b"foo");
+    assert_eq!(remote_meta.permissions().mode() & 0o777, 0o640);
+    assert_eq!(remote_meta.mtime(), local_meta.mtime());
+
+    sftp.upload_path(&td.path().join("link"), &td.path().join("remote_link"))
+        .unwrap();
+    assert_eq!(
+        fs::read_link(td.path().join("remote_link")).unwrap(),
+        Path::new("local")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn download_path() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let td = TempDir::new("foo").unwrap();
+    let remote = td.path().join("remote");
+    File::create(&remote).unwrap().write_all(b"foo").unwrap();
+    fs::set_permissions(&remote, fs::Permissions::from_mode(0o604)).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let local = td.path().join("local");
+    sftp.download_path(&remote, &local).unwrap();
+    let (local_meta, remote_meta) = (
+        fs::metadata(&local).unwrap(),
+        fs::metadata(&remote).unwrap(),
+    );
+    assert_eq!(fs::read(&local).unwrap(), b"foo");
+    assert_eq!(local_meta.permissions().mode() & 0o777, 0o604);
+    assert_eq!(local_meta.mtime(), remote_meta.mtime());
+}
+
+#[cfg(unix)]
+#[test]
+fn download_path_symlink_over_file() {
+    let td = TempDir::new("foo").unwrap();
+    let remote = td.path().join("remote");
+    std::os::unix::fs::symlink("target", &remote).unwrap();
+    let local = td.path().join("local");
+    fs::write(&local, b"old").unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    sftp.download_path(&remote, &local).unwrap();
+    assert_eq!(fs::read_link(&local).unwrap(), Path::new("target"));
+}
+
+#[test]
+fn download_path_verified() {
+    let td = TempDir::new("foo").unwrap();
+    let remote = td.path().join("remote");
+    File::create(&remote).unwrap().write_all(b"foobar").unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let local = td.path().join("local");
+    sftp.download_path_verified(&remote, &local).unwrap();
+    assert_eq!(fs::read(&local).unwrap(), b"foobar");
+}
+
+#[test]
+fn archive_reader() {
+    let td = TempDir::new("foo").unwrap();
+    fs::create_dir(&td.path().join("bar")).unwrap();
+    File::create(&td.path().join("bar/baz"))
+        .unwrap()
+        .write_all(b"baz")
+        .unwrap();
+    File::create(&td.path().join("foo")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut archive = tar::Archive::new(sftp.archive_reader(td.path()));
+    let mut entries = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        entries.push((entry.path().unwrap().into_owned(), contents));
+    }
+    let entry = |path: &str, contents: &str| (Path::new(path).to_path_buf(), contents.to_string());
+    assert_eq!(
+        entries,
+        vec![entry("bar", ""), entry("bar/baz", "baz"), entry("foo", "")]
+    );
+}
+
+#[test]
+fn sync_all() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&td.path().join("foo")).unwrap();
+    file.write_all(b"foo").unwrap();
+    file.sync_all().unwrap();
+    drop(file);
+    assert_eq!(fs::read(td.path().join("foo")).unwrap(), b"foo");
+}
+
+#[test]
+fn flush_buffers() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&td.path().join("foo")).unwrap();
+    file.flush_buffers().unwrap();
+    let data = vec![b'x'; 1024 * 1024];
+    file.write_all(&data).unwrap();
+    file.flush_buffers().unwrap();
+    file.flush().unwrap();
+    assert_eq!(file.stream_position().unwrap(), data.len() as u64);
+    assert_eq!(fs::read(td.path().join("foo")).unwrap(), data);
+}
+
+#[test]
+fn next_entry() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo")).unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut dir = sftp.opendir(td.path()).unwrap();
+    let mut names = Vec::new();
+    while let Some((name, _)) = dir.next_entry().unwrap() {
+        names.push(name);
+    }
+    names.sort();
+    assert_eq!(names, [Path::new("."), Path::new(".."), Path::new("foo")]);
+    assert!(dir.next_entry().unwrap().is_none());
+    assert!(dir.is_dir_handle());
+    assert!(!sftp.open(&td.path().join("foo")).unwrap().is_dir_handle());
+}
+
+#[test]
+fn read_dir_filtered() {
+    let td = TempDir::new("foo").unwrap();
+    File::create(&td.path().join("foo.log")).unwrap();
+    File::create(&td.path().join("bar.txt")).unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let entries = sftp
+        .read_dir_filtered(td.path(), |name, stat| {
+            stat.is_file() && name.extension() == Some("log".as_ref())
+        })
+        .unwrap();
+    let names = entries.iter().map(|e| e.0.clone()).collect::<Vec<_>>();
+    assert_eq!(names, [td.path().join("foo.log")]);
+}
+
+#[test]
+fn close_checked() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&td.path().join("foo")).unwrap();
+    file.write_all(b"foo").unwrap();
+    file.close_checked().unwrap();
+    assert_eq!(fs::read(td.path().join("foo")).unwrap(), b"foo");
+}
+
+#[test]
+fn resume_upload() {
+    let td = TempDir::new("foo").unwrap();
+    let local = td.path().join("local");
+    let remote = td.path().join("remote");
+    let data = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write(&local, &data).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+
+    // An intact prefix is kept
+    fs::write(&remote, &data[..300 * 1024]).unwrap();
+    assert_eq!(sftp.resume_upload(&local, &remote).unwrap(), 256 * 1024);
+    assert_eq!(fs::read(&remote).unwrap(), data);
+    assert_eq!(
+        sftp.resume_upload(&local, &remote).unwrap(),
+        data.len() as u64
+    );
+
+    // Past a corrupted block, data is sent again, and extra data cut off
+    let mut corrupted = data.clone();
+    corrupted[600 * 1024] ^= 1;
+    corrupted.extend_from_slice(b"extra");
+    fs::write(&remote, &corrupted).unwrap();
+    assert_eq!(sftp.resume_upload(&local, &remote).unwrap(), 512 * 1024);
+    assert_eq!(fs::read(&remote).unwrap(), data);
+}
+
+#[test]
+fn byte_counters() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&td.path().join("foo")).unwrap();
+    file.write_all(b"foobar").unwrap();
+    drop(file);
+    let mut contents = Vec::new();
+    sftp.clone()
+        .open(&td.path().join("foo"))
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(sftp.bytes_written(), 6);
+    assert_eq!(sftp.bytes_read(), 6);
+}
+
+#[test]
+fn read_at() {
+    let td = TempDir::new("foo").unwrap();
+    fs::write(td.path().join("foo"), b"foobarbaz").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.open(&td.path().join("foo")).unwrap();
+    file.set_read_ahead(1024);
+    let mut buf = [0; 3];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"foo");
+
+    assert_eq!(file.read_at(&mut buf, 6).unwrap(), 3);
+    assert_eq!(&buf, b"baz");
+    assert_eq!(file.read_at(&mut buf, 9).unwrap(), 0);
+
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"bar");
+}
+
+#[test]
+fn write_at() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&td.path().join("foo")).unwrap();
+    file.write_all(b"foo").unwrap();
+    assert_eq!(file.write_at(b"baz", 6).unwrap(), 3);
+    file.write_all(b"bar").unwrap();
+    drop(file);
+    assert_eq!(fs::read(td.path().join("foo")).unwrap(), b"foobarbaz");
+}
+
+#[test]
+fn read_write_files() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create_write(&path).unwrap();
+    file.write_all(b"foobar").unwrap();
+    file.close_checked().unwrap();
+
+    let mut file = sftp.open_read(&path).unwrap();
+    file.seek(SeekFrom::Start(3)).unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "bar");
+    assert_eq!(file.stat().unwrap().size, Some(6));
+}
+
+#[test]
+fn server_software() {
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let software = sftp.server_software().unwrap();
+    assert!(software.starts_with("OpenSSH_"), "{}", software);
+}
+
+#[test]
+fn supports_fsync() {
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    assert!(sftp.supports_fsync().unwrap());
+
+    sess.disconnect(None, "done", None).unwrap();
+    assert!(sftp.supports_fsync().is_err());
+}
+
+#[test]
+fn reopen() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&path).unwrap();
+    file.write_all(b"foobar").unwrap();
+
+    // Reopening a handle created with `TRUNCATE` must keep the data
+    let mut again = file.reopen().unwrap();
+    again.write_all(b"baz").unwrap();
+    drop(again);
+    drop(file);
+    assert_eq!(fs::read(&path).unwrap(), b"bazbar");
+
+    let mut file = sftp.open(&path).unwrap();
+    let mut buf = [0; 3];
+    file.read_exact(&mut buf).unwrap();
+    let mut again = file.reopen().unwrap();
+    let mut contents = String::new();
+    again.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "bazbar");
+}
+
+#[test]
+fn stat_cache() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    fs::write(&path, b"foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let cache = sftp.with_stat_cache(1);
+    assert_eq!(cache.stat(&path).unwrap().size, Some(3));
+
+    // Stale until invalidated
+    fs::write(&path, b"foobar").unwrap();
+    assert_eq!(cache.stat(&path).unwrap().size, Some(3));
+    cache.invalidate(&path);
+    assert_eq!(cache.stat(&path).unwrap().size, Some(6));
+
+    // Evicted once another path is cached
+    assert!(cache.lstat(td.path()).unwrap().is_dir());
+    fs::write(&path, b"f").unwrap();
+    assert_eq!(cache.stat(&path).unwrap().size, Some(1));
+}
+
+#[test]
+fn upload_sparse() {
+    let td = TempDir::new("foo").unwrap();
+    let local = td.path().join("local");
+    let remote = td.path().join("remote");
+    let mut contents = vec![0; 64 * 1024];
+    contents[10_000..10_003].copy_from_slice(b"foo");
+    contents[40_000] = 1;
+    fs::write(&local, &contents).unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let sent = sftp.upload_sparse(&local, &remote).unwrap();
+    assert_eq!(sent, 2 * 4096);
+    assert_eq!(fs::read(&remote).unwrap(), contents);
+}
+
+#[test]
+fn close_all() {
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.create(&td.path().join("foo")).unwrap();
+    let other = sftp.clone().opendir(td.path()).unwrap();
+    drop(sftp.create(&td.path().join("bar")).unwrap());
+    assert_eq!(sftp.open_handle_count(), 2);
+
+    sftp.close_all().unwrap();
+    assert_eq!(sftp.open_handle_count(), 0);
+    assert!(file.write_all(b"foo").is_err());
+    drop(other);
+}
+
+#[test]
+fn move_file() {
+    let td = TempDir::new("foo").unwrap();
+    let src = td.path().join("foo");
+    let dst = td.path().join("bar");
+    fs::write(&src, b"foo").unwrap();
+    fs::write(&dst, b"old").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    sftp.move_file(&src, &dst).unwrap();
+    assert!(!src.exists());
+    assert_eq!(fs::read(&dst).unwrap(), b"foo");
+    assert!(sftp.move_file(&src, &dst).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn move_file_renames() {
+    use std::os::unix::fs::MetadataExt;
+
+    let td = TempDir::new("foo").unwrap();
+    let src = td.path().join("foo");
+    let dst = td.path().join("bar");
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    for existing in &[false, true] {
+        fs::write(&src, b"foo").unwrap();
+        if *existing {
+            fs::write(&dst, b"old").unwrap();
+        }
+        let ino = fs::metadata(&src).unwrap().ino();
+        sftp.move_file(&src, &dst).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read(&dst).unwrap(), b"foo");
+        // Renamed rather than copied
+        assert_eq!(fs::metadata(&dst).unwrap().ino(), ino);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn write_atomic() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("config");
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    sftp.write_atomic(&path, b"foo").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"foo");
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    sftp.write_atomic(&path, b"bar").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"bar");
+    let meta = fs::metadata(&path).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::read_dir(td.path()).unwrap().count(), 1);
+
+    assert!(sftp
+        .write_atomic(&td.path().join("missing/config"), b"foo")
+        .is_err());
+    assert_eq!(fs::read_dir(td.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn touch() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    sftp.touch(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"");
+
+    fs::write(&path, b"foo").unwrap();
+    sftp.setstat(&path, ssh2::FileStat::empty().with_mtime(42))
+        .unwrap();
+    sftp.touch(&path).unwrap();
+    let stat = sftp.stat(&path).unwrap();
+    assert!(stat.mtime.unwrap() > 42);
+    assert_eq!(stat.size, Some(3));
+}
+
+#[cfg(unix)]
+#[test]
+fn dir_size() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let td = TempDir::new("foo").unwrap();
+    fs::write(td.path().join("foo"), b"foo").unwrap();
+    fs::create_dir(td.path().join("bar")).unwrap();
+    fs::write(td.path().join("bar/baz"), b"bazbaz").unwrap();
+    std::os::unix::fs::symlink("foo", td.path().join("link")).unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    assert_eq!(sftp.dir_size(td.path()).unwrap(), 9);
+
+    let locked = td.path().join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::write(locked.join("secret"), b"secret").unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    let res = sftp.dir_size_skipping(td.path());
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    let (total, skipped) = res.unwrap();
+    assert_eq!(total, 9);
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, locked);
+}
+
+#[test]
+fn on_would_block() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let td = TempDir::new("foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    sftp.on_would_block(move |_| {
+        let _ = counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    sess.set_blocking(false);
+    let mut would_block = 0;
+    loop {
+        match sftp.stat(td.path()) {
+            Err(ref e) if e.code() == -37 => would_block += 1, // LIBSSH2_ERROR_EAGAIN
+            res => {
+                res.unwrap();
+                break;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    sess.set_blocking(true);
+    assert!(would_block > 0);
+    assert_eq!(calls.load(Ordering::SeqCst), would_block);
+}
+
+#[test]
+fn read_exact_fast() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    let contents = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write(&path, &contents).unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.open(&path).unwrap();
+    file.set_read_ahead(4096);
+    let mut buf = [0; 10];
+    file.read_exact(&mut buf).unwrap();
+
+    let mut rest = vec![0; contents.len() - 10];
+    file.read_exact_fast(&mut rest).unwrap();
+    assert_eq!(&buf[..], &contents[..10]);
+    assert!(rest == contents[10..]);
+    assert!(file.read_exact_fast(&mut buf).is_err());
+}
+
+#[test]
+fn file_last_error() {
+    let td = TempDir::new("foo").unwrap();
+    let path = td.path().join("foo");
+    fs::write(&path, b"foo").unwrap();
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let mut file = sftp.open(&path).unwrap();
+    assert!(file.last_error().is_none());
+
+    // The handle is read only, so the server rejects the write.
+    assert!(file.write(b"bar").is_err());
+    let err = file.last_error().unwrap();
+    assert_ne!(err.code(), -31); // LIBSSH2_ERROR_SFTP_PROTOCOL
+    assert!(err.code() > 0);
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).unwrap();
+    assert!(file.last_error().is_some());
+}
+
+#[test]
+fn mirror() {
+    use std::sync::{Arc, Mutex};
+
+    let td = TempDir::new("foo").unwrap();
+    let remote = td.path().join("remote");
+    fs::create_dir_all(remote.join("sub/deeper")).unwrap();
+    fs::write(remote.join("a"), b"foo").unwrap();
+    fs::write(remote.join("sub/b"), b"bar").unwrap();
+    fs::write(remote.join("sub/deeper/c"), b"baz").unwrap();
+    let local = td.path().join("local");
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let downloaded = Arc::new(Mutex::new(Vec::new()));
+    let skipped = Arc::new(Mutex::new(Vec::new()));
+    let new_mirror = || {
+        let mut mirror = sftp.mirror(&remote, &local);
+        let downloaded = downloaded.clone();
+        mirror.on_download(move |name, _| downloaded.lock().unwrap().push(name.to_path_buf()));
+        let skipped = skipped.clone();
+        mirror.on_skip(move |name, _| skipped.lock().unwrap().push(name.to_path_buf()));
+        mirror
+    };
+
+    // Stop after the first file of `sub`, and pick up from the saved state
+    let mut mirror = new_mirror();
+    while downloaded.lock().unwrap().len() < 2 {
+        assert!(mirror.step().unwrap());
+    }
+    let state = mirror.state().unwrap();
+    drop(mirror);
+    let mut mirror = new_mirror();
+    mirror.resume(&state).unwrap();
+    mirror.run().unwrap();
+    assert!(mirror.is_finished());
+    assert!(!mirror.step().unwrap());
+    assert_eq!(fs::read(local.join("a")).unwrap(), b"foo");
+    assert_eq!(fs::read(local.join("sub/b")).unwrap(), b"bar");
+    assert_eq!(fs::read(local.join("sub/deeper/c")).unwrap(), b"baz");
+    assert_eq!(downloaded.lock().unwrap().len(), 3);
+    assert_eq!(*skipped.lock().unwrap(), vec![Path::new("sub/b")]);
+
+    // Only what changed is downloaded again
+    downloaded.lock().unwrap().clear();
+    skipped.lock().unwrap().clear();
+    fs::write(remote.join("sub/b"), b"longer").unwrap();
+    new_mirror().run().unwrap();
+    assert_eq!(*downloaded.lock().unwrap(), vec![Path::new("sub/b")]);
+    assert_eq!(skipped.lock().unwrap().len(), 2);
+    assert_eq!(fs::read(local.join("sub/b")).unwrap(), b"longer");
+
+    // Errors can be skipped
+    fs::remove_file(local.join("sub/b")).unwrap();
+    fs::create_dir(local.join("sub/b")).unwrap();
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let mut mirror = new_mirror();
+    assert!(mirror.run().is_err());
+    {
+        let errors = errors.clone();
+        mirror.on_error(move |name, _| {
+            errors.lock().unwrap().push(name.to_path_buf());
+            true
+        });
+    }
+    mirror.run().unwrap();
+    assert_eq!(*errors.lock().unwrap(), vec![Path::new("sub/b")]);
+
+    assert!(new_mirror().resume(b"garbage").is_err());
+}
+
+#[test]
+fn transfer_rate() {
+    use std::time::{Duration, Instant};
+
+    let td = TempDir::new("foo").unwrap();
+    let local = td.path().join("local");
+    let remote = td.path().join("remote");
+    let contents = (0..32 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write(&local, &contents).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    sftp.set_transfer_rate(Some(16 * 1024));
+    let start = Instant::now();
+    sftp.upload_path(&local, &remote).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+    assert_eq!(fs::read(&remote).unwrap(), contents);
+
+    let start = Instant::now();
+    sftp.download_path(&remote, &local).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+    assert_eq!(fs::read(&local).unwrap(), contents);
+
+    sftp.set_transfer_rate(None);
+    let start = Instant::now();
+    sftp.download_path(&remote, &local).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(1500));
+}
+
+#[cfg(unix)]
+#[test]
+fn walk() {
+    use std::os::unix::fs::symlink;
+
+    let td = TempDir::new("foo").unwrap();
+    fs::create_dir_all(td.path().join("a/b")).unwrap();
+    fs::write(td.path().join("a/b/file"), b"foo").unwrap();
+    symlink("..", td.path().join("a/b/up")).unwrap();
+    symlink("a/b", td.path().join("link")).unwrap();
+
+    let sess = ::authed_session();
+    let sftp = sess.sftp().unwrap();
+    let names = |walk: ssh2::Walk| {
+        walk.map(|entry| match entry {
+            Ok((path, stat)) => {
+                let name = path.strip_prefix(td.path()).unwrap();
+                (name.to_str().unwrap().to_string(), stat.is_dir())
+            }
+            Err(err) => (format!("error {}", err.code()), false),
+        })
+        .collect::<Vec<_>>()
+    };
+    let entry = |name: &str, dir| (name.to_string(), dir);
+
+    assert_eq!(
+        names(sftp.walk(td.path())),
+        vec![
+            entry("a", true),
+            entry("a/b", true),
+            entry("a/b/file", false),
+            entry("a/b/up", false),
+            entry("link", false),
+        ]
+    );
+    // `a/b/up` leads back to `a`, and `link` to `a/b`, both already walked
+    assert_eq!(
+        names(sftp.walk(td.path()).follow_symlinks(true)),
+        vec![
+            entry("a", true),
+            entry("a/b", true),
+            entry("a/b/file", false),
+            entry("error -34", false), // LIBSSH2_ERROR_INVAL
+            entry("error -34", false),
+        ]
+    );
+
+    let mut walk = sftp.walk(&td.path().join("missing"));
+    assert!(walk.next().unwrap().is_err());
+    assert!(walk.next().is_none());
 }